@@ -31,9 +31,9 @@ pub fn push_bits(n: &mut u16, bit_to_push: u8) {
     if bit_to_push > 1 {
         panic!("???? bit to push should to either 0 or 1");
     }
-    *n = *n << 1;
+    *n <<= 1;
     if bit_to_push != 0 {
-        *n = *n + 0b1;
+        *n += 0b1;
     }
 }
 
@@ -42,6 +42,7 @@ mod tests {
     use crate::common::binary_macros::push_bits;
 
     #[test]
+    #[allow(clippy::unusual_byte_groupings)]
     fn test_push_bits() {
         let mut n = 0;
         push_bits(&mut n, 1);
@@ -1,6 +1,4 @@
-use anyhow::bail;
-
-use crate::common::ParseError;
+use crate::common::{ParseError, ParseResult};
 
 /// Crude implementation of Reader, probably can use Cursor but wanted to code this myself :D
 #[derive(Debug)]
@@ -11,31 +9,77 @@ pub struct DnsReader<'a> {
 
 impl<'a> DnsReader<'a> {
     pub fn new(buf: &'a [u8]) -> Self {
-        Self {
-            buf,
-            cur_pos: 0,
-        }
+        Self { buf, cur_pos: 0 }
     }
 
-    pub fn read_exact(&mut self, target_buf: &mut [u8]) -> anyhow::Result<()> {
+    pub fn read_exact(&mut self, target_buf: &mut [u8]) -> ParseResult<()> {
         self.peek_exact(target_buf)?;
         self.cur_pos += target_buf.len();
         Ok(())
     }
 
-    pub fn peek_exact(&mut self, target_buf: &mut [u8]) -> anyhow::Result<()> {
+    pub fn peek_exact(&mut self, target_buf: &mut [u8]) -> ParseResult<()> {
         let len = target_buf.len();
         let upto = self.cur_pos + len;
         let buf_len = self.buf.len();
         if upto > buf_len {
-            bail!(ParseError::BufOverflow {
+            return Err(ParseError::Truncated {
                 buf_length: buf_len,
-                cur_pos: self.cur_pos,
-                n: len
-            })
+                offset: self.cur_pos,
+                n: len,
+            });
         }
         let source_buf = &self.buf[self.cur_pos..upto];
         target_buf.copy_from_slice(source_buf);
         Ok(())
     }
+
+    pub fn read_u8(&mut self) -> ParseResult<u8> {
+        let mut buf: [u8; 1] = [0; 1];
+        self.read_exact(&mut buf)?;
+        Ok(buf[0])
+    }
+
+    pub fn read_u16(&mut self) -> ParseResult<u16> {
+        let mut buf: [u8; 2] = [0; 2];
+        self.read_exact(&mut buf)?;
+        Ok(u16::from_be_bytes(buf))
+    }
+
+    pub fn read_u32(&mut self) -> ParseResult<u32> {
+        let mut buf: [u8; 4] = [0; 4];
+        self.read_exact(&mut buf)?;
+        Ok(u32::from_be_bytes(buf))
+    }
+
+    pub fn read_vec(&mut self, n: usize) -> ParseResult<Vec<u8>> {
+        let mut buf = vec![0; n];
+        self.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::common::ParseError;
+
+    use super::DnsReader;
+
+    #[test]
+    fn test_read_past_end() {
+        let buf = [1, 2, 3];
+        let mut reader = DnsReader::new(&buf);
+        assert_eq!(reader.read_u16(), Ok(0x0102));
+        assert_eq!(
+            reader.read_u16(),
+            Err(ParseError::Truncated {
+                offset: 2,
+                n: 2,
+                buf_length: 3
+            })
+        );
+        // failed read doesn't move the cursor
+        assert_eq!(reader.cur_pos, 2);
+        assert_eq!(reader.read_u8(), Ok(3));
+    }
 }
@@ -0,0 +1,140 @@
+use std::collections::HashMap;
+
+use crate::dns::label::Label;
+
+/// Pointers only have 14 bits for the offset
+const MAX_POINTER_OFFSET: usize = 0x3FFF;
+
+/// Packet level writer, the counterpart of [`super::dns_reader::DnsReader`].
+/// Remembers where every name suffix was written so later names can point to it
+/// https://www.rfc-editor.org/rfc/rfc1035#section-4.1.4
+#[derive(Debug, Default)]
+pub struct DnsWriter {
+    pub buf: Vec<u8>,
+    names: HashMap<String, usize>,
+}
+
+impl DnsWriter {
+    pub fn new() -> Self {
+        Self {
+            // in most cases DNS packet shouldn't be more than 512 bytes
+            buf: Vec::with_capacity(512),
+            names: HashMap::new(),
+        }
+    }
+
+    pub fn pos(&self) -> usize {
+        self.buf.len()
+    }
+
+    pub fn write(&mut self, bytes: &[u8]) {
+        self.buf.extend(bytes);
+    }
+
+    pub fn write_u16(&mut self, n: u16) {
+        self.buf.extend(n.to_be_bytes());
+    }
+
+    pub fn write_u32(&mut self, n: u32) {
+        self.buf.extend(n.to_be_bytes());
+    }
+
+    /// Overwrite bytes that were already written, used to fill in lengths afterwards
+    pub fn set(&mut self, pos: usize, bytes: &[u8]) {
+        self.buf[pos..pos + bytes.len()].copy_from_slice(bytes);
+    }
+
+    pub fn set_u16(&mut self, pos: usize, n: u16) {
+        self.set(pos, &n.to_be_bytes());
+    }
+
+    /// Throw away everything written from `pos` on, names in there can't be pointed to anymore
+    pub fn truncate(&mut self, pos: usize) {
+        self.buf.truncate(pos);
+        self.names.retain(|_, offset| *offset < pos);
+    }
+
+    /// Write the name, replacing the longest suffix that was already written with a pointer
+    pub fn write_label(&mut self, label: &Label) {
+        let parts = label
+            .0
+            .split('.')
+            .filter(|part| !part.is_empty())
+            .collect::<Vec<_>>();
+        for i in 0..parts.len() {
+            let suffix = parts[i..].join(".");
+            if let Some(offset) = self.names.get(&suffix) {
+                self.write_u16(0xC000 | *offset as u16);
+                return;
+            }
+            if self.pos() <= MAX_POINTER_OFFSET {
+                self.names.insert(suffix, self.pos());
+            }
+            self.buf.push(parts[i].len() as u8);
+            self.buf.extend(parts[i].as_bytes());
+        }
+        // Label end is represented by 0x00
+        self.buf.push(0x00);
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use crate::dns::label::Label;
+
+    use super::DnsWriter;
+
+    #[test]
+    fn test_write_label_compression() {
+        let mut writer = DnsWriter::new();
+        writer.write_label(&Label("example.com".to_string()));
+        writer.write_label(&Label("www.example.com".to_string()));
+        writer.write_label(&Label("example.com".to_string()));
+        writer.write_label(&Label("mail.example.org".to_string()));
+        writer.write_label(&Label("".to_string()));
+        assert_eq!(
+            writer.into_bytes(),
+            vec![
+                7, 101, 120, 97, 109, 112, 108, 101, 3, 99, 111, 109, 0, // example.com
+                3, 119, 119, 119, 0xC0, 0, // www + pointer to example.com
+                0xC0, 0, // example.com
+                4, 109, 97, 105, 108, 7, 101, 120, 97, 109, 112, 108, 101, 3, 111, 114, 103,
+                0, // mail.example.org, only full suffixes are compressed
+                0, // root
+            ]
+        );
+    }
+
+    #[test]
+    fn test_truncate_forgets_names() {
+        let mut writer = DnsWriter::new();
+        writer.write_label(&Label("com".to_string()));
+        let pos = writer.pos();
+        writer.write_label(&Label("example.com".to_string()));
+        writer.truncate(pos);
+        writer.write_label(&Label("www.example.com".to_string()));
+        assert_eq!(
+            writer.into_bytes(),
+            vec![
+                3, 99, 111, 109, 0, // com
+                3, 119, 119, 119, 7, 101, 120, 97, 109, 112, 108, 101, 0xC0, 0,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_no_pointer_past_max_offset() {
+        let mut writer = DnsWriter::new();
+        writer.write(&vec![0; 0x4000]);
+        writer.write_label(&Label("example.com".to_string()));
+        let len = writer.pos();
+        writer.write_label(&Label("example.com".to_string()));
+        assert_eq!(writer.pos() - len, 13);
+    }
+}
@@ -1,26 +1,51 @@
 use thiserror::Error;
 
-use self::dns_reader::DnsReader;
+use self::{dns_reader::DnsReader, dns_writer::DnsWriter};
 
 pub mod binary_macros;
 pub mod dns_reader;
+pub mod dns_writer;
 
 pub trait AsBytes {
     fn as_bytes(&self) -> Vec<u8>;
 }
 
-#[derive(Error, Debug)]
+/// Like [`AsBytes`] but writes into a packet, so names can be compressed against what is already
+/// in there
+pub trait WriteTo {
+    fn write_to(&self, writer: &mut DnsWriter);
+}
+
+/// Everything that can go wrong while reading a packet from the wire.
+/// Every variant carries the offset in the packet where the problem was found
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
 pub enum ParseError {
-    #[error("Unable to parse the data")]
-    ParseError(#[from] std::io::Error),
-    #[error("Unable to read {n} bytes, current position = {cur_pos}, buf_length = {buf_length}")]
-    BufOverflow {
-        cur_pos: usize,
+    #[error("Unable to read {n} bytes, current position = {offset}, buf_length = {buf_length}")]
+    Truncated {
+        offset: usize,
         n: usize,
         buf_length: usize,
     },
+    #[error("Bad label at offset {offset}: {reason}")]
+    BadLabel { offset: usize, reason: String },
+    #[error("Compression pointer at offset {offset} to {target} doesn't point backward")]
+    BadPointer { offset: usize, target: usize },
+    #[error("Too many compression pointers followed, last one at offset {offset}")]
+    PointerLoop { offset: usize },
+    #[error("Name longer than 255 bytes at offset {offset}")]
+    NameTooLong { offset: usize },
+    #[error("Invalid {field} value {value} at offset {offset}")]
+    BadValue {
+        offset: usize,
+        field: &'static str,
+        value: u16,
+    },
+    #[error("Bad rdata at offset {offset}: {reason}")]
+    BadRData { offset: usize, reason: String },
 }
 
-pub trait Parse {
-    fn parse(reader: &mut DnsReader) -> Self;
+pub type ParseResult<T> = Result<T, ParseError>;
+
+pub trait Parse: Sized {
+    fn parse(reader: &mut DnsReader) -> ParseResult<Self>;
 }
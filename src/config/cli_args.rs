@@ -1,29 +1,257 @@
-use std::{collections::HashMap, sync::OnceLock};
+use std::{fmt::Display, path::PathBuf, str::FromStr};
 
-use tracing::debug;
+use thiserror::Error;
 
-static CLI_ARGS: OnceLock<HashMap<String, String>> = OnceLock::new();
+use super::{
+    logging::{LogFilter, LogFormat},
+    settings::{Addr, Mode, Settings},
+};
 
-pub struct CliArgs {}
+pub const USAGE: &str = "\
+Usage: dns-starter-rust [OPTIONS]
+
+Options:
+      --port <PORT>          Port to listen on for UDP and TCP [default: 2053]
+      --bind <ADDR>          Address to listen on, IP or IP:PORT, can be repeated [default: 0.0.0.0]
+      --resolver <ADDR>      Upstream resolver, IP or IP:PORT, can be repeated, tried in order
+      --log-level <FILTER>   Level or RUST_LOG style filter, e.g. warn,dns_starter_rust=debug [default: info]
+      --log-format <FORMAT>  compact or json [default: compact]
+      --config <PATH>        Configuration file
+      --mode <MODE>          forward or mock [default: forward with a resolver, mock otherwise]
+  -h, --help                 Print help
+  -V, --version              Print version
+
+Options override the DNS_SERVER_* environment variables, which override the config file.
+The log file, rate limit and zones can only be set in the config file.";
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum CliError {
+    #[error("unknown option '{0}'")]
+    UnknownOption(String),
+    #[error("unexpected argument '{0}'")]
+    UnexpectedArgument(String),
+    #[error("option '{0}' needs a value")]
+    MissingValue(&'static str),
+    #[error("invalid value '{value}' for '{option}': {reason}")]
+    InvalidValue {
+        option: &'static str,
+        value: String,
+        reason: String,
+    },
+}
+
+/// What the command line asks for
+#[derive(Debug, PartialEq, Eq)]
+pub enum CliCommand {
+    Run(CliArgs),
+    Help,
+    Version,
+}
+
+/// Typed command line options, see [`USAGE`]. Options that are not given are left for the
+/// environment and the config file, see [`super::Config::load`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CliArgs {
+    pub port: Option<u16>,
+    pub bind: Vec<Addr>,
+    pub resolvers: Vec<Addr>,
+    pub log_filter: Option<LogFilter>,
+    pub log_format: Option<LogFormat>,
+    pub config: Option<PathBuf>,
+    pub mode: Option<Mode>,
+}
 
 impl CliArgs {
-    pub fn port() -> String {
-        let args = CLI_ARGS.get().expect("ARGS is not initialized");
-        args.get("--port")
-            .map(|s| s.clone())
-            .unwrap_or("2053".to_string())
-    }
-    pub fn resolver() -> Option<String> {
-        let args = CLI_ARGS.get().expect("ARGS is not initialized");
-        args.get("--resolver").map(|s| s.clone())
-    }
-    pub fn init() {
-        let arg_vec = std::env::args().collect::<Vec<String>>();
-        let params = arg_vec[1..]
-            .chunks(2)
-            .map(|chunk| (chunk[0].clone(), chunk[1].clone()))
-            .collect::<HashMap<_, _>>();
-        debug!("CLI args: {:?}", params);
-        CLI_ARGS.set(params).expect("unable to set ARGS once lock");
+    /// Parses the arguments without the program name. Options take their value either as the
+    /// next argument or after `=`, e.g. `--port 53` or `--port=53`
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<CliCommand, CliError> {
+        let mut cli_args = CliArgs::default();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let (option, inline_value) = match arg.split_once('=') {
+                Some((option, value)) if option.starts_with("--") => {
+                    (option.to_string(), Some(value.to_string()))
+                }
+                _ => (arg.clone(), None),
+            };
+            let option: &'static str = match option.as_str() {
+                "-h" | "--help" => return Ok(CliCommand::Help),
+                "-V" | "--version" => return Ok(CliCommand::Version),
+                "--port" => "--port",
+                "--bind" => "--bind",
+                "--resolver" => "--resolver",
+                "--log-level" => "--log-level",
+                "--log-format" => "--log-format",
+                "--config" => "--config",
+                "--mode" => "--mode",
+                _ if arg.starts_with('-') => return Err(CliError::UnknownOption(option)),
+                _ => return Err(CliError::UnexpectedArgument(arg)),
+            };
+            let value = match inline_value.or_else(|| args.next()) {
+                Some(value) => value,
+                None => return Err(CliError::MissingValue(option)),
+            };
+            match option {
+                "--port" => cli_args.port = Some(parse_value(option, &value)?),
+                "--bind" => cli_args.bind.push(parse_value(option, &value)?),
+                "--resolver" => cli_args.resolvers.push(parse_value(option, &value)?),
+                "--log-level" => cli_args.log_filter = Some(parse_value(option, &value)?),
+                "--log-format" => cli_args.log_format = Some(parse_value(option, &value)?),
+                "--config" => cli_args.config = Some(PathBuf::from(value)),
+                "--mode" => cli_args.mode = Some(parse_value(option, &value)?),
+                _ => unreachable!("every option is matched above"),
+            }
+        }
+        Ok(CliCommand::Run(cli_args))
+    }
+
+    pub fn settings(&self) -> Settings {
+        Settings {
+            port: self.port,
+            bind: (!self.bind.is_empty()).then(|| self.bind.clone()),
+            resolvers: (!self.resolvers.is_empty()).then(|| self.resolvers.clone()),
+            mode: self.mode,
+            log_filter: self.log_filter.clone(),
+            log_format: self.log_format,
+            ..Default::default()
+        }
+    }
+}
+
+fn parse_value<T>(option: &'static str, value: &str) -> Result<T, CliError>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    value.parse::<T>().map_err(|e| CliError::InvalidValue {
+        option,
+        value: value.to_string(),
+        reason: e.to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use pretty_assertions::assert_eq;
+
+    use crate::config::{
+        logging::LogFormat,
+        settings::{Addr, Mode},
+    };
+
+    use super::{CliArgs, CliCommand, CliError};
+
+    fn parse(args: &[&str]) -> Result<CliCommand, CliError> {
+        CliArgs::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    fn run(args: &[&str]) -> CliArgs {
+        match parse(args) {
+            Ok(CliCommand::Run(args)) => args,
+            other => panic!("expected run, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_defaults() {
+        assert_eq!(run(&[]), CliArgs::default());
+    }
+
+    #[test]
+    fn test_all_options() {
+        let args = run(&[
+            "--port",
+            "53",
+            "--bind=127.0.0.1",
+            "--bind",
+            "[::1]:5353",
+            "--resolver",
+            "8.8.8.8",
+            "--resolver=1.1.1.1:5353",
+            "--log-level",
+            "warn,dns_starter_rust::dns=debug",
+            "--log-format=json",
+            "--config",
+            "dns.toml",
+            "--mode",
+            "mock",
+        ]);
+        assert_eq!(
+            args,
+            CliArgs {
+                port: Some(53),
+                bind: vec![
+                    Addr::Ip("127.0.0.1".parse().unwrap()),
+                    Addr::Socket("[::1]:5353".parse().unwrap())
+                ],
+                resolvers: vec![
+                    Addr::Ip("8.8.8.8".parse().unwrap()),
+                    Addr::Socket("1.1.1.1:5353".parse().unwrap())
+                ],
+                log_filter: Some("warn,dns_starter_rust::dns=debug".parse().unwrap()),
+                log_format: Some(LogFormat::Json),
+                config: Some(PathBuf::from("dns.toml")),
+                mode: Some(Mode::Mock),
+            }
+        );
+        let settings = args.settings();
+        assert_eq!(settings.port, Some(53));
+        assert_eq!(settings.bind.unwrap().len(), 2);
+        assert!(run(&[]).settings().bind.is_none());
+    }
+
+    #[test]
+    fn test_help_and_version() {
+        assert_eq!(parse(&["--port", "53", "--help"]), Ok(CliCommand::Help));
+        assert_eq!(parse(&["-h"]), Ok(CliCommand::Help));
+        assert_eq!(parse(&["-V"]), Ok(CliCommand::Version));
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(parse(&["--port"]), Err(CliError::MissingValue("--port")));
+        assert_eq!(
+            parse(&["--port", "70000"]),
+            Err(CliError::InvalidValue {
+                option: "--port",
+                value: "70000".to_string(),
+                reason: "number too large to fit in target type".to_string(),
+            })
+        );
+        assert!(matches!(
+            parse(&["--resolver", "dns.google"]),
+            Err(CliError::InvalidValue {
+                option: "--resolver",
+                ..
+            })
+        ));
+        assert!(matches!(
+            parse(&["--log-level", "dns_starter_rust=loud"]),
+            Err(CliError::InvalidValue {
+                option: "--log-level",
+                ..
+            })
+        ));
+        assert!(matches!(
+            parse(&["--mode", "cache"]),
+            Err(CliError::InvalidValue {
+                option: "--mode",
+                ..
+            })
+        ));
+        assert_eq!(
+            parse(&["--verbose"]),
+            Err(CliError::UnknownOption("--verbose".to_string()))
+        );
+        assert_eq!(
+            parse(&["--verbose=1"]),
+            Err(CliError::UnknownOption("--verbose".to_string()))
+        );
+        assert_eq!(
+            parse(&["53"]),
+            Err(CliError::UnexpectedArgument("53".to_string()))
+        );
     }
 }
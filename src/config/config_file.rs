@@ -0,0 +1,444 @@
+use std::{
+    fmt::Display,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use anyhow::{bail, Context};
+use serde::Deserialize;
+
+use crate::{dns::zone::Zone, fdbg};
+
+use super::{
+    logging::{LogFile, LogFilter, LogFormat, Rotation},
+    settings::{RateLimit, Settings},
+};
+
+/// Layout of the TOML config file, every key is optional
+/// ```toml
+/// [server]
+/// port = 53
+/// bind = ["0.0.0.0", "[::1]:5353"]
+/// mode = "forward"
+/// workers = 16
+/// queue_size = 1024
+/// tcp_idle_timeout_ms = 10000
+/// shutdown_timeout_ms = 5000
+///
+/// [upstream]
+/// resolvers = ["8.8.8.8", "1.1.1.1:53"]
+/// timeout_ms = 5000
+///
+/// [log]
+/// level = "info,dns_starter_rust::dns::server=debug"
+/// format = "json"
+/// file = "/var/log/dns-server.log"
+/// rotation = "daily"
+/// max_files = 7
+///
+/// [policy]
+/// allow = ["127.0.0.0/8", "192.168.0.0/16"]
+/// block = ["ads.example.com"]
+/// rate_limit = { per_second = 20, burst = 40 }
+///
+/// [cache]
+/// size = 10000
+/// stale_window_secs = 86400
+/// file = "/var/lib/dns-server/cache"
+///
+/// [[zones]]
+/// name = "home.lan"
+/// records = [{ name = "nas", type = "A", ttl = 300, data = "192.168.1.10" }]
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    #[serde(default)]
+    server: ServerSection,
+    #[serde(default)]
+    upstream: UpstreamSection,
+    #[serde(default)]
+    log: LogSection,
+    #[serde(default)]
+    policy: PolicySection,
+    #[serde(default)]
+    cache: CacheSection,
+    zones: Option<Vec<ZoneSection>>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ServerSection {
+    port: Option<u16>,
+    bind: Option<Vec<String>>,
+    mode: Option<String>,
+    workers: Option<usize>,
+    queue_size: Option<usize>,
+    tcp_idle_timeout_ms: Option<u64>,
+    shutdown_timeout_ms: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct UpstreamSection {
+    resolvers: Option<Vec<String>>,
+    timeout_ms: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LogSection {
+    level: Option<String>,
+    format: Option<String>,
+    file: Option<PathBuf>,
+    rotation: Option<String>,
+    max_files: Option<usize>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PolicySection {
+    allow: Option<Vec<String>>,
+    block: Option<Vec<String>>,
+    rate_limit: Option<RateLimitSection>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RateLimitSection {
+    per_second: u32,
+    burst: u32,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CacheSection {
+    /// 0 turns the cache off
+    size: Option<usize>,
+    /// 0 never answers with expired data
+    stale_window_secs: Option<u64>,
+    file: Option<PathBuf>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ZoneSection {
+    name: String,
+    #[serde(default)]
+    records: Vec<RecordSection>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RecordSection {
+    name: String,
+    #[serde(rename = "type")]
+    typez: String,
+    ttl: u32,
+    data: String,
+}
+
+/// Reads and validates the config file, errors name the key that is wrong
+pub fn load(path: &Path) -> anyhow::Result<Settings> {
+    let text = std::fs::read_to_string(path).context(fdbg!("Unable to read {path:?}"))?;
+    parse(&text).with_context(|| format!("Invalid config file {}", path.display()))
+}
+
+pub fn parse(text: &str) -> anyhow::Result<Settings> {
+    let file: ConfigFile = match serde_path_to_error::deserialize(toml::Deserializer::new(text)) {
+        Ok(file) => file,
+        Err(e) => {
+            let line = e
+                .inner()
+                .span()
+                .map(|span| text[..span.start].matches('\n').count() + 1);
+            let key = e.path().to_string();
+            let message = e.inner().message().trim();
+            match (key.as_str(), line) {
+                (".", Some(line)) => bail!("line {line}: {message}"),
+                (".", None) => bail!("{message}"),
+                (key, Some(line)) => bail!("{key} (line {line}): {message}"),
+                (key, None) => bail!("{key}: {message}"),
+            }
+        }
+    };
+
+    let ConfigFile {
+        server,
+        upstream,
+        log,
+        policy,
+        cache,
+        zones,
+    } = file;
+    Ok(Settings {
+        port: server.port,
+        bind: parse_list("server.bind", server.bind)?,
+        resolvers: parse_list("upstream.resolvers", upstream.resolvers)?,
+        mode: parse_value("server.mode", server.mode)?,
+        log_filter: parse_value::<LogFilter>("log.level", log.level)?,
+        log_format: parse_value::<LogFormat>("log.format", log.format)?,
+        log_file: match log.file {
+            Some(path) => Some(LogFile {
+                path,
+                rotation: parse_value::<Rotation>("log.rotation", log.rotation)?
+                    .unwrap_or_default(),
+                max_files: at_least_one("log.max_files", log.max_files)?,
+            }),
+            None if log.rotation.is_some() || log.max_files.is_some() => {
+                bail!("log.file: needed for log.rotation and log.max_files")
+            }
+            None => None,
+        },
+        workers: at_least_one("server.workers", server.workers)?,
+        queue_size: at_least_one("server.queue_size", server.queue_size)?,
+        tcp_idle_timeout: millis("server.tcp_idle_timeout_ms", server.tcp_idle_timeout_ms)?,
+        upstream_timeout: millis("upstream.timeout_ms", upstream.timeout_ms)?,
+        shutdown_timeout: millis("server.shutdown_timeout_ms", server.shutdown_timeout_ms)?,
+        allow: parse_list("policy.allow", policy.allow)?,
+        block: policy.block,
+        rate_limit: match policy.rate_limit {
+            Some(limit) if limit.per_second == 0 => {
+                bail!("policy.rate_limit.per_second: must be at least 1")
+            }
+            Some(limit) => Some(RateLimit {
+                per_second: limit.per_second,
+                burst: limit.burst,
+            }),
+            None => None,
+        },
+        zones: zones.map(parse_zones).transpose()?,
+        cache_size: cache.size,
+        stale_window: cache.stale_window_secs.map(Duration::from_secs),
+        cache_file: cache.file,
+    })
+}
+
+fn parse_zones(zones: Vec<ZoneSection>) -> anyhow::Result<Vec<Zone>> {
+    zones
+        .into_iter()
+        .enumerate()
+        .map(|(i, section)| {
+            let mut zone = match Zone::new(&section.name) {
+                Ok(zone) => zone,
+                Err(e) => bail!("zones[{i}].name: {e}"),
+            };
+            for (j, record) in section.records.iter().enumerate() {
+                if let Err(e) =
+                    zone.add_record(&record.name, &record.typez, record.ttl, &record.data)
+                {
+                    bail!("zones[{i}].records[{j}]: {e}");
+                }
+            }
+            Ok(zone)
+        })
+        .collect()
+}
+
+fn parse_value<T>(key: &str, value: Option<String>) -> anyhow::Result<Option<T>>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    value
+        .map(|value| {
+            value
+                .parse::<T>()
+                .map_err(|e| anyhow::anyhow!("{key}: invalid value '{value}', {e}"))
+        })
+        .transpose()
+}
+
+fn parse_list<T>(key: &str, values: Option<Vec<String>>) -> anyhow::Result<Option<Vec<T>>>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    values
+        .map(|values| {
+            values
+                .into_iter()
+                .enumerate()
+                .map(|(i, value)| {
+                    value
+                        .parse::<T>()
+                        .map_err(|e| anyhow::anyhow!("{key}[{i}]: invalid value '{value}', {e}"))
+                })
+                .collect()
+        })
+        .transpose()
+}
+
+pub(super) fn at_least_one(key: &str, value: Option<usize>) -> anyhow::Result<Option<usize>> {
+    match value {
+        Some(0) => bail!("{key}: must be at least 1"),
+        value => Ok(value),
+    }
+}
+
+pub(super) fn millis(key: &str, value: Option<u64>) -> anyhow::Result<Option<Duration>> {
+    match value {
+        Some(0) => bail!("{key}: must be at least 1"),
+        value => Ok(value.map(Duration::from_millis)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use pretty_assertions::assert_eq;
+
+    use crate::config::{
+        logging::{LogFile, LogFormat, Rotation},
+        settings::{Addr, Mode, RateLimit},
+    };
+
+    use super::parse;
+
+    #[test]
+    fn test_parse_full() {
+        let settings = parse(
+            r#"
+            [server]
+            port = 53
+            bind = ["127.0.0.1", "[::1]:5353"]
+            mode = "forward"
+            workers = 4
+            queue_size = 64
+            tcp_idle_timeout_ms = 2000
+            shutdown_timeout_ms = 1000
+
+            [upstream]
+            resolvers = ["8.8.8.8"]
+            timeout_ms = 1500
+
+            [log]
+            level = "warn,dns_starter_rust=debug"
+            format = "json"
+            file = "/var/log/dns.log"
+            rotation = "hourly"
+            max_files = 24
+
+            [policy]
+            allow = ["127.0.0.0/8"]
+            block = ["ads.example.com"]
+            rate_limit = { per_second = 20, burst = 40 }
+
+            [cache]
+            size = 500
+            stale_window_secs = 600
+            file = "/tmp/dns.cache"
+
+            [[zones]]
+            name = "home.lan"
+            records = [{ name = "nas", type = "A", ttl = 300, data = "192.168.1.10" }]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(settings.port, Some(53));
+        assert_eq!(
+            settings.bind,
+            Some(vec![
+                Addr::Ip("127.0.0.1".parse().unwrap()),
+                Addr::Socket("[::1]:5353".parse().unwrap())
+            ])
+        );
+        assert_eq!(settings.mode, Some(Mode::Forward));
+        assert_eq!(settings.workers, Some(4));
+        assert_eq!(settings.queue_size, Some(64));
+        assert_eq!(settings.tcp_idle_timeout, Some(Duration::from_secs(2)));
+        assert_eq!(settings.shutdown_timeout, Some(Duration::from_secs(1)));
+        assert_eq!(
+            settings.resolvers,
+            Some(vec![Addr::Ip("8.8.8.8".parse().unwrap())])
+        );
+        assert_eq!(settings.upstream_timeout, Some(Duration::from_millis(1500)));
+        assert_eq!(
+            settings.log_filter,
+            Some("warn,dns_starter_rust=debug".parse().unwrap())
+        );
+        assert_eq!(settings.log_format, Some(LogFormat::Json));
+        assert_eq!(
+            settings.log_file,
+            Some(LogFile {
+                path: "/var/log/dns.log".into(),
+                rotation: Rotation::Hourly,
+                max_files: Some(24),
+            })
+        );
+        assert_eq!(settings.allow.unwrap().len(), 1);
+        assert_eq!(settings.block, Some(vec!["ads.example.com".to_string()]));
+        assert_eq!(
+            settings.rate_limit,
+            Some(RateLimit {
+                per_second: 20,
+                burst: 40
+            })
+        );
+        assert_eq!(settings.cache_size, Some(500));
+        assert_eq!(settings.stale_window, Some(Duration::from_secs(600)));
+        assert_eq!(settings.cache_file, Some("/tmp/dns.cache".into()));
+        let zones = settings.zones.unwrap();
+        assert_eq!(zones[0].name.0, "home.lan");
+        assert_eq!(zones[0].records[0].label.0, "nas.home.lan");
+    }
+
+    #[test]
+    fn test_parse_empty() {
+        let settings = parse("").unwrap();
+        assert_eq!(settings.port, None);
+        assert!(settings.zones.is_none());
+    }
+
+    fn error(text: &str) -> String {
+        format!("{:#}", parse(text).unwrap_err())
+    }
+
+    #[test]
+    fn test_errors_name_the_key() {
+        assert_eq!(
+            error("[server]\nport = 70000"),
+            "server.port (line 2): invalid value: integer `70000`, expected u16"
+        );
+        assert_eq!(
+            error("[server]\nworker = 4"),
+            "server.worker (line 2): unknown field `worker`, expected one of `port`, `bind`, \
+             `mode`, `workers`, `queue_size`, `tcp_idle_timeout_ms`, `shutdown_timeout_ms`"
+        );
+        assert_eq!(
+            error("[server]\nworkers = 0"),
+            "server.workers: must be at least 1"
+        );
+        assert_eq!(
+            error("[upstream]\nresolvers = [\"8.8.8.8\", \"dns.google\"]"),
+            "upstream.resolvers[1]: invalid value 'dns.google', expected an IP address with \
+             an optional port"
+        );
+        assert_eq!(
+            error("[log]\nlevel = \"info,dns_starter_rust=loud\""),
+            "log.level: invalid value 'info,dns_starter_rust=loud', invalid filter directive"
+        );
+        assert_eq!(
+            error("[log]\nrotation = \"daily\""),
+            "log.file: needed for log.rotation and log.max_files"
+        );
+        assert_eq!(
+            error("[policy]\nallow = [\"10.0.0.0/40\"]"),
+            "policy.allow[0]: invalid value '10.0.0.0/40', prefix length is over 32"
+        );
+        assert_eq!(
+            error("[[zones]]\nname = \"home.lan\"\nrecords = [{ name = \"nas\", type = \"A\", ttl = 1, data = \"x\" }]"),
+            "zones[0].records[0]: invalid IPv4 address 'x'"
+        );
+        assert_eq!(
+            error("[[zones]]\nname = \"home..lan\""),
+            "zones[0].name: invalid name 'home..lan', label '' must be 1 to 63 bytes"
+        );
+        assert_eq!(
+            error("[server"),
+            "line 1: invalid table header\nexpected `.`, `]`"
+        );
+    }
+}
@@ -0,0 +1,213 @@
+use std::{fmt::Display, path::PathBuf, str::FromStr, time::Duration};
+
+use anyhow::anyhow;
+
+use super::{
+    config_file::{at_least_one, millis},
+    logging::{LogFilter, LogFormat},
+    settings::{Mode, Settings},
+};
+
+/// Every variable starts with this
+pub const ENV_PREFIX: &str = "DNS_SERVER_";
+
+/// Settings from `DNS_SERVER_*` variables, lists are comma separated
+/// - `DNS_SERVER_PORT`
+/// - `DNS_SERVER_BIND`
+/// - `DNS_SERVER_RESOLVERS`
+/// - `DNS_SERVER_MODE`
+/// - `DNS_SERVER_LOG_LEVEL`, or `RUST_LOG` when not set
+/// - `DNS_SERVER_LOG_FORMAT`
+/// - `DNS_SERVER_WORKERS`
+/// - `DNS_SERVER_QUEUE_SIZE`
+/// - `DNS_SERVER_TCP_IDLE_TIMEOUT_MS`
+/// - `DNS_SERVER_UPSTREAM_TIMEOUT_MS`
+/// - `DNS_SERVER_SHUTDOWN_TIMEOUT_MS`
+/// - `DNS_SERVER_ALLOW`
+/// - `DNS_SERVER_BLOCK`
+/// - `DNS_SERVER_CACHE_SIZE`
+/// - `DNS_SERVER_CACHE_STALE_WINDOW_SECS`
+/// - `DNS_SERVER_CACHE_FILE`
+/// - `DNS_SERVER_CONFIG`, read by [`super::Config::load`]
+///
+/// The log file, the rate limit and zones only come from the config file
+pub fn settings(var: impl Fn(&str) -> Option<String>) -> anyhow::Result<Settings> {
+    let rust_log = var("RUST_LOG");
+    let var = |name: &str| var(&format!("{ENV_PREFIX}{name}"));
+    Ok(Settings {
+        port: parse_value("PORT", var("PORT"))?,
+        bind: parse_list("BIND", var("BIND"))?,
+        resolvers: parse_list("RESOLVERS", var("RESOLVERS"))?,
+        mode: parse_value::<Mode>("MODE", var("MODE"))?,
+        log_filter: match var("LOG_LEVEL") {
+            Some(filter) => parse_value::<LogFilter>("LOG_LEVEL", Some(filter))?,
+            None => rust_log
+                .map(|filter| {
+                    filter
+                        .parse::<LogFilter>()
+                        .map_err(|e| anyhow!("RUST_LOG: invalid value '{filter}', {e}"))
+                })
+                .transpose()?,
+        },
+        log_format: parse_value::<LogFormat>("LOG_FORMAT", var("LOG_FORMAT"))?,
+        workers: at_least_one(
+            &format!("{ENV_PREFIX}WORKERS"),
+            parse_value("WORKERS", var("WORKERS"))?,
+        )?,
+        queue_size: at_least_one(
+            &format!("{ENV_PREFIX}QUEUE_SIZE"),
+            parse_value("QUEUE_SIZE", var("QUEUE_SIZE"))?,
+        )?,
+        tcp_idle_timeout: parse_millis("TCP_IDLE_TIMEOUT_MS", var("TCP_IDLE_TIMEOUT_MS"))?,
+        upstream_timeout: parse_millis("UPSTREAM_TIMEOUT_MS", var("UPSTREAM_TIMEOUT_MS"))?,
+        shutdown_timeout: parse_millis("SHUTDOWN_TIMEOUT_MS", var("SHUTDOWN_TIMEOUT_MS"))?,
+        allow: parse_list("ALLOW", var("ALLOW"))?,
+        block: parse_list("BLOCK", var("BLOCK"))?,
+        cache_size: parse_value("CACHE_SIZE", var("CACHE_SIZE"))?,
+        stale_window: parse_value("CACHE_STALE_WINDOW_SECS", var("CACHE_STALE_WINDOW_SECS"))?
+            .map(Duration::from_secs),
+        cache_file: var("CACHE_FILE").map(PathBuf::from),
+        ..Default::default()
+    })
+}
+
+fn parse_value<T>(name: &str, value: Option<String>) -> anyhow::Result<Option<T>>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    value
+        .map(|value| {
+            value
+                .parse::<T>()
+                .map_err(|e| anyhow!("{ENV_PREFIX}{name}: invalid value '{value}', {e}"))
+        })
+        .transpose()
+}
+
+fn parse_millis(name: &str, value: Option<String>) -> anyhow::Result<Option<Duration>> {
+    millis(&format!("{ENV_PREFIX}{name}"), parse_value(name, value)?)
+}
+
+fn parse_list<T>(name: &str, value: Option<String>) -> anyhow::Result<Option<Vec<T>>>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    value
+        .map(|value| {
+            value
+                .split(',')
+                .map(str::trim)
+                .filter(|value| !value.is_empty())
+                .map(|value| {
+                    value
+                        .parse::<T>()
+                        .map_err(|e| anyhow!("{ENV_PREFIX}{name}: invalid value '{value}', {e}"))
+                })
+                .collect()
+        })
+        .transpose()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, time::Duration};
+
+    use pretty_assertions::assert_eq;
+
+    use crate::config::{
+        logging::LogFormat,
+        settings::{Addr, Mode},
+    };
+
+    use super::settings;
+
+    #[test]
+    fn test_env_settings() {
+        let vars = HashMap::from([
+            ("DNS_SERVER_PORT", "5353"),
+            ("DNS_SERVER_RESOLVERS", "8.8.8.8, 1.1.1.1:53"),
+            ("DNS_SERVER_MODE", "forward"),
+            ("PORT", "1"),
+        ]);
+        let env = settings(|name| vars.get(name).map(|v| v.to_string())).unwrap();
+        assert_eq!(env.port, Some(5353));
+        assert_eq!(env.bind, None);
+        assert_eq!(
+            env.resolvers,
+            Some(vec![
+                Addr::Ip("8.8.8.8".parse().unwrap()),
+                Addr::Socket("1.1.1.1:53".parse().unwrap())
+            ])
+        );
+        assert_eq!(env.mode, Some(Mode::Forward));
+
+        let error =
+            settings(|name| (name == "DNS_SERVER_PORT").then(|| "http".to_string())).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "DNS_SERVER_PORT: invalid value 'http', invalid digit found in string"
+        );
+    }
+
+    #[test]
+    fn test_log_filter_from_rust_log() {
+        let mut vars = HashMap::from([("RUST_LOG", "debug"), ("DNS_SERVER_LOG_FORMAT", "json")]);
+        let env = settings(|name| vars.get(name).map(|v| v.to_string())).unwrap();
+        assert_eq!(env.log_filter, Some("debug".parse().unwrap()));
+        assert_eq!(env.log_format, Some(LogFormat::Json));
+
+        vars.insert("DNS_SERVER_LOG_LEVEL", "warn,dns_starter_rust=trace");
+        let env = settings(|name| vars.get(name).map(|v| v.to_string())).unwrap();
+        assert_eq!(
+            env.log_filter,
+            Some("warn,dns_starter_rust=trace".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn test_server_and_cache_settings() {
+        let vars = HashMap::from([
+            ("DNS_SERVER_WORKERS", "8"),
+            ("DNS_SERVER_QUEUE_SIZE", "128"),
+            ("DNS_SERVER_TCP_IDLE_TIMEOUT_MS", "2000"),
+            ("DNS_SERVER_UPSTREAM_TIMEOUT_MS", "1500"),
+            ("DNS_SERVER_SHUTDOWN_TIMEOUT_MS", "100"),
+            ("DNS_SERVER_ALLOW", "127.0.0.0/8, 10.0.0.0/8"),
+            ("DNS_SERVER_BLOCK", "ads.example.com"),
+            ("DNS_SERVER_CACHE_SIZE", "0"),
+            ("DNS_SERVER_CACHE_STALE_WINDOW_SECS", "600"),
+            ("DNS_SERVER_CACHE_FILE", "/tmp/dns.cache"),
+        ]);
+        let env = settings(|name| vars.get(name).map(|v| v.to_string())).unwrap();
+        assert_eq!(env.workers, Some(8));
+        assert_eq!(env.queue_size, Some(128));
+        assert_eq!(env.tcp_idle_timeout, Some(Duration::from_secs(2)));
+        assert_eq!(env.upstream_timeout, Some(Duration::from_millis(1500)));
+        assert_eq!(env.shutdown_timeout, Some(Duration::from_millis(100)));
+        assert_eq!(env.allow.unwrap().len(), 2);
+        assert_eq!(env.block, Some(vec!["ads.example.com".to_string()]));
+        assert_eq!(env.cache_size, Some(0));
+        assert_eq!(env.stale_window, Some(Duration::from_secs(600)));
+        assert_eq!(env.cache_file, Some("/tmp/dns.cache".into()));
+
+        let error = |name: &str, value: &str| {
+            settings(|var| (var == name).then(|| value.to_string()))
+                .unwrap_err()
+                .to_string()
+        };
+        assert_eq!(
+            error("DNS_SERVER_WORKERS", "0"),
+            "DNS_SERVER_WORKERS: must be at least 1"
+        );
+        assert_eq!(
+            error("DNS_SERVER_UPSTREAM_TIMEOUT_MS", "0"),
+            "DNS_SERVER_UPSTREAM_TIMEOUT_MS: must be at least 1"
+        );
+        assert_eq!(
+            error("DNS_SERVER_QUEUE_SIZE", "many"),
+            "DNS_SERVER_QUEUE_SIZE: invalid value 'many', invalid digit found in string"
+        );
+    }
+}
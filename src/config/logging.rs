@@ -0,0 +1,200 @@
+use std::{
+    fmt::{self, Display},
+    path::PathBuf,
+    str::FromStr,
+};
+
+use anyhow::{anyhow, Context};
+use tracing::subscriber::set_global_default;
+use tracing_appender::{
+    non_blocking::WorkerGuard,
+    rolling::{self, RollingFileAppender},
+};
+use tracing_subscriber::{
+    fmt::writer::BoxMakeWriter, layer::SubscriberExt, reload, EnvFilter, Layer, Registry,
+};
+
+use crate::fdbg;
+
+/// Filter used when none is configured
+pub const DEFAULT_LOG_FILTER: &str = "info";
+
+/// `RUST_LOG` style filter, a default level and per module levels,
+/// e.g. `warn,dns_starter_rust::dns::server=debug`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogFilter(String);
+
+impl Default for LogFilter {
+    fn default() -> Self {
+        LogFilter(DEFAULT_LOG_FILTER.to_string())
+    }
+}
+
+impl FromStr for LogFilter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        EnvFilter::try_new(s).map_err(|e| e.to_string())?;
+        Ok(LogFilter(s.to_string()))
+    }
+}
+
+impl Display for LogFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl LogFilter {
+    fn env_filter(&self) -> EnvFilter {
+        EnvFilter::try_new(&self.0).expect("filter is validated when parsed")
+    }
+}
+
+/// How every log line is written
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    /// One short line per event, for people
+    #[default]
+    Compact,
+    /// One JSON object per event, for log collectors
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "compact" => Ok(LogFormat::Compact),
+            "json" => Ok(LogFormat::Json),
+            _ => Err("expected compact or json".to_string()),
+        }
+    }
+}
+
+/// When the log file starts over with a new one
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Rotation {
+    Hourly,
+    Daily,
+    #[default]
+    Never,
+}
+
+impl FromStr for Rotation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "hourly" => Ok(Rotation::Hourly),
+            "daily" => Ok(Rotation::Daily),
+            "never" => Ok(Rotation::Never),
+            _ => Err("expected hourly, daily or never".to_string()),
+        }
+    }
+}
+
+/// Log to a file instead of stdout. Rotated files get the date appended to `path`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogFile {
+    pub path: PathBuf,
+    pub rotation: Rotation,
+    /// Oldest rotated files are deleted past this many, every file is kept when not set
+    pub max_files: Option<usize>,
+}
+
+impl LogFile {
+    fn appender(&self) -> anyhow::Result<RollingFileAppender> {
+        let name = self
+            .path
+            .file_name()
+            .ok_or_else(|| anyhow!("log file {} has no file name", self.path.display()))?;
+        let directory = match self.path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => std::path::Path::new("."),
+        };
+        let rotation = match self.rotation {
+            Rotation::Hourly => rolling::Rotation::HOURLY,
+            Rotation::Daily => rolling::Rotation::DAILY,
+            Rotation::Never => rolling::Rotation::NEVER,
+        };
+        let mut builder = RollingFileAppender::builder()
+            .rotation(rotation)
+            .filename_prefix(name.to_string_lossy());
+        if let Some(max_files) = self.max_files {
+            builder = builder.max_log_files(max_files);
+        }
+        builder
+            .build(directory)
+            .context(fdbg!("Unable to open log file {}", self.path.display()))
+    }
+}
+
+/// Everything about logging, see [`setup_log`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LogConfig {
+    pub filter: LogFilter,
+    pub format: LogFormat,
+    /// Stdout when not set
+    pub file: Option<LogFile>,
+}
+
+/// Keeps logging running, the filter can be changed while the server runs.
+/// Lines still buffered for the log file are written out when this is dropped
+pub struct LogHandle {
+    filter: reload::Handle<EnvFilter, Registry>,
+    _file_guard: Option<WorkerGuard>,
+}
+
+impl LogHandle {
+    pub fn set_filter(&self, filter: &LogFilter) -> anyhow::Result<()> {
+        self.filter
+            .reload(filter.env_filter())
+            .context(fdbg!("Unable to change the log filter"))
+    }
+}
+
+pub fn setup_log(config: &LogConfig) -> anyhow::Result<LogHandle> {
+    color_eyre::install().expect("Unable to setup color eyre");
+    let (filter, filter_handle) = reload::Layer::new(config.filter.env_filter());
+    let (writer, file_guard) = match &config.file {
+        Some(file) => {
+            let (writer, guard) = tracing_appender::non_blocking(file.appender()?);
+            (BoxMakeWriter::new(writer), Some(guard))
+        }
+        None => (BoxMakeWriter::new(std::io::stdout), None),
+    };
+    let fmt = tracing_subscriber::fmt::layer()
+        .with_writer(writer)
+        // colors only make sense on a terminal
+        .with_ansi(config.file.is_none());
+    let fmt = match config.format {
+        LogFormat::Compact => fmt.compact().boxed(),
+        LogFormat::Json => fmt.json().boxed(),
+    };
+    set_global_default(Registry::default().with(filter).with(fmt))?;
+    Ok(LogHandle {
+        filter: filter_handle,
+        _file_guard: file_guard,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::{LogFilter, LogFormat, Rotation};
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(LogFilter::default().to_string(), "info");
+        let filter = "warn,dns_starter_rust::dns::server=debug";
+        assert_eq!(filter.parse::<LogFilter>().unwrap().to_string(), filter);
+        assert!("dns_starter_rust=loud".parse::<LogFilter>().is_err());
+        assert_eq!("json".parse::<LogFormat>(), Ok(LogFormat::Json));
+        assert!("pretty".parse::<LogFormat>().is_err());
+        assert_eq!("daily".parse::<Rotation>(), Ok(Rotation::Daily));
+        assert!("weekly".parse::<Rotation>().is_err());
+    }
+}
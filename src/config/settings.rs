@@ -0,0 +1,372 @@
+use std::{
+    fmt::{self, Display},
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::PathBuf,
+    str::FromStr,
+    time::Duration,
+};
+
+use anyhow::bail;
+
+use crate::dns::{
+    cache::{DEFAULT_CACHE_SIZE, DEFAULT_STALE_WINDOW},
+    resolver::UPSTREAM_TIMEOUT,
+    server::{
+        middleware::IpNetwork,
+        server_handle::SHUTDOWN_DEADLINE,
+        tcp_listener::TCP_IDLE_TIMEOUT,
+        worker_pool::{DEFAULT_QUEUE_SIZE, DEFAULT_WORKERS},
+    },
+    zone::Zone,
+};
+
+use super::{
+    cli_args::CliArgs,
+    config_file, env,
+    logging::{LogConfig, LogFile, LogFilter, LogFormat},
+};
+
+pub const DEFAULT_PORT: u16 = 2053;
+/// Port used for a resolver given without one
+pub const DNS_PORT: u16 = 53;
+
+/// How queries are answered
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// Send queries to the upstream resolvers
+    Forward,
+    /// Answer every query with a made up A record
+    Mock,
+}
+
+impl FromStr for Mode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "forward" => Ok(Mode::Forward),
+            "mock" => Ok(Mode::Mock),
+            _ => Err("expected forward or mock".to_string()),
+        }
+    }
+}
+
+impl Display for Mode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Mode::Forward => write!(f, "forward"),
+            Mode::Mock => write!(f, "mock"),
+        }
+    }
+}
+
+/// Address given with or without a port, the port is filled in once every source is merged
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Addr {
+    Ip(IpAddr),
+    Socket(SocketAddr),
+}
+
+impl Addr {
+    pub fn with_port(self, port: u16) -> SocketAddr {
+        match self {
+            Addr::Ip(ip) => SocketAddr::new(ip, port),
+            Addr::Socket(addr) => addr,
+        }
+    }
+}
+
+impl FromStr for Addr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(addr) = s.parse::<SocketAddr>() {
+            return Ok(Addr::Socket(addr));
+        }
+        // IPv6 without a port may come in brackets as well
+        let ip = s.trim_start_matches('[').trim_end_matches(']');
+        ip.parse::<IpAddr>()
+            .map(Addr::Ip)
+            .map_err(|_| "expected an IP address with an optional port".to_string())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+    pub per_second: u32,
+    pub burst: u32,
+}
+
+/// Settings from one source, the command line, the environment or the config file.
+/// Unset values are taken from the next source
+#[derive(Debug, Clone, Default)]
+pub struct Settings {
+    pub port: Option<u16>,
+    pub bind: Option<Vec<Addr>>,
+    pub resolvers: Option<Vec<Addr>>,
+    pub mode: Option<Mode>,
+    pub log_filter: Option<LogFilter>,
+    pub log_format: Option<LogFormat>,
+    pub log_file: Option<LogFile>,
+    pub workers: Option<usize>,
+    pub queue_size: Option<usize>,
+    pub tcp_idle_timeout: Option<Duration>,
+    pub upstream_timeout: Option<Duration>,
+    pub shutdown_timeout: Option<Duration>,
+    pub allow: Option<Vec<IpNetwork>>,
+    pub block: Option<Vec<String>>,
+    pub rate_limit: Option<RateLimit>,
+    pub zones: Option<Vec<Zone>>,
+    pub cache_size: Option<usize>,
+    pub stale_window: Option<Duration>,
+    pub cache_file: Option<PathBuf>,
+}
+
+impl Settings {
+    /// Values of `self` win, the rest comes from `fallback`
+    pub fn or(self, fallback: Settings) -> Settings {
+        Settings {
+            port: self.port.or(fallback.port),
+            bind: self.bind.or(fallback.bind),
+            resolvers: self.resolvers.or(fallback.resolvers),
+            mode: self.mode.or(fallback.mode),
+            log_filter: self.log_filter.or(fallback.log_filter),
+            log_format: self.log_format.or(fallback.log_format),
+            log_file: self.log_file.or(fallback.log_file),
+            workers: self.workers.or(fallback.workers),
+            queue_size: self.queue_size.or(fallback.queue_size),
+            tcp_idle_timeout: self.tcp_idle_timeout.or(fallback.tcp_idle_timeout),
+            upstream_timeout: self.upstream_timeout.or(fallback.upstream_timeout),
+            shutdown_timeout: self.shutdown_timeout.or(fallback.shutdown_timeout),
+            allow: self.allow.or(fallback.allow),
+            block: self.block.or(fallback.block),
+            rate_limit: self.rate_limit.or(fallback.rate_limit),
+            zones: self.zones.or(fallback.zones),
+            cache_size: self.cache_size.or(fallback.cache_size),
+            stale_window: self.stale_window.or(fallback.stale_window),
+            cache_file: self.cache_file.or(fallback.cache_file),
+        }
+    }
+}
+
+/// Everything the server runs with, merged from every source with the defaults filled in
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub listen: Vec<SocketAddr>,
+    pub resolvers: Vec<SocketAddr>,
+    pub mode: Mode,
+    pub log: LogConfig,
+    pub workers: usize,
+    pub queue_size: usize,
+    pub tcp_idle_timeout: Duration,
+    pub upstream_timeout: Duration,
+    pub shutdown_timeout: Duration,
+    /// Networks allowed to query, everyone when not set
+    pub allow: Option<Vec<IpNetwork>>,
+    /// Names answered with NXDOMAIN, along with every name below them
+    pub block: Vec<String>,
+    pub rate_limit: Option<RateLimit>,
+    pub zones: Vec<Zone>,
+    /// Upstream responses kept, nothing is cached when 0
+    pub cache_size: usize,
+    /// How long expired responses are still served while upstream fails, never when 0
+    pub stale_window: Duration,
+    /// Where the cache is saved on shutdown and loaded from on startup
+    pub cache_file: Option<PathBuf>,
+}
+
+impl Config {
+    /// Command line over environment over config file over defaults. The config file comes
+    /// from `--config` or `DNS_SERVER_CONFIG`
+    pub fn load(cli_args: &CliArgs) -> anyhow::Result<Config> {
+        Self::load_from(cli_args, |name| std::env::var(name).ok())
+    }
+
+    fn load_from(
+        cli_args: &CliArgs,
+        var: impl Fn(&str) -> Option<String>,
+    ) -> anyhow::Result<Config> {
+        let path = cli_args
+            .config
+            .clone()
+            .or_else(|| var(&format!("{}CONFIG", env::ENV_PREFIX)).map(PathBuf::from));
+        let file = match path {
+            Some(path) => config_file::load(&path)?,
+            None => Settings::default(),
+        };
+        let settings = cli_args.settings().or(env::settings(var)?).or(file);
+        Config::try_from(settings)
+    }
+}
+
+impl Config {
+    /// Keys that only take effect after a restart, the listeners, threads and log output are
+    /// set up once
+    pub fn restart_needed(&self, new: &Config) -> Vec<&'static str> {
+        [
+            ("listen", self.listen != new.listen),
+            ("workers", self.workers != new.workers),
+            ("queue_size", self.queue_size != new.queue_size),
+            (
+                "tcp_idle_timeout",
+                self.tcp_idle_timeout != new.tcp_idle_timeout,
+            ),
+            (
+                "shutdown_timeout",
+                self.shutdown_timeout != new.shutdown_timeout,
+            ),
+            ("log_format", self.log.format != new.log.format),
+            ("log_file", self.log.file != new.log.file),
+            ("cache_size", self.cache_size != new.cache_size),
+            ("stale_window", self.stale_window != new.stale_window),
+        ]
+        .into_iter()
+        .filter_map(|(key, changed)| changed.then_some(key))
+        .collect()
+    }
+}
+
+impl TryFrom<Settings> for Config {
+    type Error = anyhow::Error;
+
+    fn try_from(settings: Settings) -> Result<Self, Self::Error> {
+        let port = settings.port.unwrap_or(DEFAULT_PORT);
+        let bind = settings
+            .bind
+            .unwrap_or(vec![Addr::Ip(Ipv4Addr::UNSPECIFIED.into())]);
+        let resolvers = settings
+            .resolvers
+            .unwrap_or_default()
+            .into_iter()
+            .map(|addr| addr.with_port(DNS_PORT))
+            .collect::<Vec<_>>();
+        let mode = match settings.mode {
+            Some(Mode::Forward) if resolvers.is_empty() => {
+                bail!("mode is forward but no upstream resolver is configured")
+            }
+            Some(mode) => mode,
+            None if resolvers.is_empty() => Mode::Mock,
+            None => Mode::Forward,
+        };
+        if bind.is_empty() {
+            bail!("no listen address is configured");
+        }
+        Ok(Config {
+            listen: bind.into_iter().map(|addr| addr.with_port(port)).collect(),
+            resolvers,
+            mode,
+            log: LogConfig {
+                filter: settings.log_filter.unwrap_or_default(),
+                format: settings.log_format.unwrap_or_default(),
+                file: settings.log_file,
+            },
+            workers: settings.workers.unwrap_or(DEFAULT_WORKERS),
+            queue_size: settings.queue_size.unwrap_or(DEFAULT_QUEUE_SIZE),
+            tcp_idle_timeout: settings.tcp_idle_timeout.unwrap_or(TCP_IDLE_TIMEOUT),
+            upstream_timeout: settings.upstream_timeout.unwrap_or(UPSTREAM_TIMEOUT),
+            shutdown_timeout: settings.shutdown_timeout.unwrap_or(SHUTDOWN_DEADLINE),
+            allow: settings.allow,
+            block: settings.block.unwrap_or_default(),
+            rate_limit: settings.rate_limit,
+            zones: settings.zones.unwrap_or_default(),
+            cache_size: settings.cache_size.unwrap_or(DEFAULT_CACHE_SIZE),
+            stale_window: settings.stale_window.unwrap_or(DEFAULT_STALE_WINDOW),
+            cache_file: settings.cache_file,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, time::Duration};
+
+    use pretty_assertions::assert_eq;
+
+    use crate::config::{cli_args::CliArgs, logging::LogFormat};
+
+    use super::{Addr, Config, Mode, Settings};
+
+    #[test]
+    fn test_defaults() {
+        let config = Config::try_from(Settings::default()).unwrap();
+        assert_eq!(config.listen, vec!["0.0.0.0:2053".parse().unwrap()]);
+        assert_eq!(config.mode, Mode::Mock);
+        assert_eq!(config.log.filter.to_string(), "info");
+        assert_eq!(config.log.format, LogFormat::Compact);
+        assert!(config.log.file.is_none());
+        assert_eq!(config.upstream_timeout, Duration::from_secs(5));
+        assert!(config.allow.is_none());
+    }
+
+    #[test]
+    fn test_precedence() {
+        let path = std::env::temp_dir().join(format!("dns-config-{}.toml", std::process::id()));
+        std::fs::write(
+            &path,
+            "[server]\nport = 1053\nbind = [\"127.0.0.1\"]\nworkers = 3\n\n[log]\nlevel = \"warn\"\nformat = \"json\"",
+        )
+        .unwrap();
+        let cli_args = CliArgs {
+            bind: vec![Addr::Ip("::1".parse().unwrap())],
+            ..Default::default()
+        };
+        let vars = HashMap::from([
+            ("DNS_SERVER_CONFIG", path.to_string_lossy().to_string()),
+            ("DNS_SERVER_PORT", "2153".to_string()),
+            ("DNS_SERVER_BIND", "10.0.0.1".to_string()),
+            ("DNS_SERVER_RESOLVERS", "8.8.8.8".to_string()),
+            ("RUST_LOG", "debug".to_string()),
+        ]);
+        let config = Config::load_from(&cli_args, |name| vars.get(name).cloned()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        // bind from the command line, port from the environment
+        assert_eq!(config.listen, vec!["[::1]:2153".parse().unwrap()]);
+        assert_eq!(config.resolvers, vec!["8.8.8.8:53".parse().unwrap()]);
+        assert_eq!(config.mode, Mode::Forward);
+        assert_eq!(config.workers, 3);
+        assert_eq!(config.log.filter.to_string(), "debug");
+        assert_eq!(config.log.format, LogFormat::Json);
+    }
+
+    #[test]
+    fn test_forward_without_resolver() {
+        let settings = Settings {
+            mode: Some(Mode::Forward),
+            ..Default::default()
+        };
+        assert_eq!(
+            Config::try_from(settings).unwrap_err().to_string(),
+            "mode is forward but no upstream resolver is configured"
+        );
+    }
+
+    #[test]
+    fn test_missing_config_file() {
+        let cli_args = CliArgs {
+            config: Some("/nonexistent/dns.toml".into()),
+            ..Default::default()
+        };
+        assert!(Config::load_from(&cli_args, |_| None).is_err());
+    }
+
+    #[test]
+    fn test_restart_needed() {
+        let config = Config::try_from(Settings::default()).unwrap();
+        let reloaded = Config::try_from(Settings {
+            port: Some(53),
+            workers: Some(2),
+            resolvers: Some(vec![Addr::Ip("8.8.8.8".parse().unwrap())]),
+            block: Some(vec!["ads.example.com".to_string()]),
+            log_filter: Some("debug".parse().unwrap()),
+            log_format: Some(LogFormat::Json),
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(
+            config.restart_needed(&reloaded),
+            vec!["listen", "workers", "log_format"]
+        );
+        assert!(config.restart_needed(&config.clone()).is_empty());
+    }
+}
@@ -1,7 +1,11 @@
+use std::fmt::{self, Display};
+use std::net::Ipv4Addr;
+
 use crate::common::dns_reader::DnsReader;
-use crate::common::{AsBytes, Parse};
+use crate::common::dns_writer::DnsWriter;
+use crate::common::{AsBytes, Parse, ParseError, ParseResult, WriteTo};
 
-use super::{label::Label, RecordClass, RecordType};
+use super::{edns::EdnsOption, label::Label, RecordClass, RecordType};
 
 #[derive(Debug, Clone)]
 pub struct Answer {
@@ -25,73 +29,401 @@ impl AsBytes for Answer {
         buf
     }
 }
+impl WriteTo for Answer {
+    fn write_to(&self, writer: &mut DnsWriter) {
+        writer.write_label(&self.label);
+        writer.write(&self.typez.as_bytes());
+        writer.write(&self.class.as_bytes());
+        writer.write_u32(self.ttl);
+        // RD length is only known once rdata is written
+        let len_pos = writer.pos();
+        writer.write_u16(0);
+        self.rdata.write_to(writer);
+        let len = writer.pos() - len_pos - 2;
+        writer.set_u16(len_pos, len as u16);
+    }
+}
 impl Answer {
-    pub fn parse_ttl(reader: &mut DnsReader) -> u32 {
-        let mut buf: [u8; 4] = [0; 4];
-        reader.read_exact(&mut buf).expect("Unable to read ttl");
-        u32::from_be_bytes(buf)
+    pub fn parse_ttl(reader: &mut DnsReader) -> ParseResult<u32> {
+        reader.read_u32()
+    }
+}
+
+/// Zone file line, e.g. `example.com. 60 IN A 8.8.8.8`
+impl Display for Answer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} {} {} {}",
+            self.label, self.ttl, self.class, self.typez, self.rdata
+        )
     }
 }
 
 impl Parse for Answer {
-    fn parse(reader: &mut DnsReader) -> Self {
-        let label = Label::parse(reader);
-        let typez = RecordType::parse(reader);
-        let class = RecordClass::parse(reader);
-        let ttl = Answer::parse_ttl(reader);
-        let rdata = RData::parse(reader);
-
-        Self {
+    fn parse(reader: &mut DnsReader) -> ParseResult<Self> {
+        let label = Label::parse(reader)?;
+        let typez = RecordType::parse(reader)?;
+        let class = RecordClass::parse(reader)?;
+        let ttl = Answer::parse_ttl(reader)?;
+        let rdata = RData::parse(reader, &typez)?;
+
+        Ok(Self {
             label,
             typez,
             class,
             ttl,
             rdata,
-        }
+        })
     }
 }
 
-#[derive(Debug, Clone)]
-pub struct RData(pub String);
-
-impl Parse for RData {
-    fn parse(reader: &mut DnsReader) -> Self {
-        // RD Length
-        let mut buf: [u8; 2] = [0; 2];
-        reader
-            .read_exact(&mut buf)
-            .expect("Unable to read rd length");
-        let rd_length = u16::from_be_bytes(buf);
-        let mut rdata = vec![0; rd_length as usize];
-        reader.read_exact(&mut rdata).expect("Unable to read rdata");
-        let str = rdata
-            .iter()
-            .map(|r| r.to_string())
-            .fold(String::new(), |acc, x| acc + &x + ".");
-        let str = str.trim_end_matches('.');
-        Self(str.to_string())
+/// Typed RDATA for the record types listed in [`RecordType`]
+/// https://www.rfc-editor.org/rfc/rfc1035#section-3.3
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RData {
+    A(Ipv4Addr),
+    NS(Label),
+    MD(Label),
+    MF(Label),
+    CNAME(Label),
+    SOA {
+        mname: Label,
+        rname: Label,
+        serial: u32,
+        refresh: u32,
+        retry: u32,
+        expire: u32,
+        minimum: u32,
+    },
+    MB(Label),
+    MG(Label),
+    MR(Label),
+    NULL(Vec<u8>),
+    WKS {
+        address: Ipv4Addr,
+        protocol: u8,
+        bitmap: Vec<u8>,
+    },
+    PTR(Label),
+    HINFO {
+        cpu: Vec<u8>,
+        os: Vec<u8>,
+    },
+    MINFO {
+        rmailbx: Label,
+        emailbx: Label,
+    },
+    MX {
+        preference: u16,
+        exchange: Label,
+    },
+    /// One or more <character-string>s, kept as raw bytes as they need not be UTF-8
+    TXT(Vec<Vec<u8>>),
+    /// Options of the EDNS pseudo-record, see [`super::edns::Edns`]
+    OPT(Vec<EdnsOption>),
+    /// RDATA of a type without typed representation, kept opaque so it can be relayed as is
+    /// https://www.rfc-editor.org/rfc/rfc3597
+    Unknown(Vec<u8>),
+}
+
+impl RData {
+    /// RDATA can't be parsed on its own, the record type decides the layout.
+    /// Reads RDLENGTH followed by RDATA, names inside RDATA may point anywhere in the packet
+    pub fn parse(reader: &mut DnsReader, typez: &RecordType) -> ParseResult<Self> {
+        let rd_length = reader.read_u16()? as usize;
+        let start = reader.cur_pos;
+        let end = start + rd_length;
+        if end > reader.buf.len() {
+            return Err(ParseError::Truncated {
+                offset: start,
+                n: rd_length,
+                buf_length: reader.buf.len(),
+            });
+        }
+        let rdata = match typez {
+            RecordType::A => RData::A(Self::parse_ipv4(reader)?),
+            RecordType::NS => RData::NS(Label::parse(reader)?),
+            RecordType::MD => RData::MD(Label::parse(reader)?),
+            RecordType::MF => RData::MF(Label::parse(reader)?),
+            RecordType::CNAME => RData::CNAME(Label::parse(reader)?),
+            RecordType::SOA => RData::SOA {
+                mname: Label::parse(reader)?,
+                rname: Label::parse(reader)?,
+                serial: reader.read_u32()?,
+                refresh: reader.read_u32()?,
+                retry: reader.read_u32()?,
+                expire: reader.read_u32()?,
+                minimum: reader.read_u32()?,
+            },
+            RecordType::MB => RData::MB(Label::parse(reader)?),
+            RecordType::MG => RData::MG(Label::parse(reader)?),
+            RecordType::MR => RData::MR(Label::parse(reader)?),
+            RecordType::NULL => RData::NULL(Self::read_rest(reader, end)?),
+            RecordType::WKS => RData::WKS {
+                address: Self::parse_ipv4(reader)?,
+                protocol: reader.read_u8()?,
+                bitmap: Self::read_rest(reader, end)?,
+            },
+            RecordType::PTR => RData::PTR(Label::parse(reader)?),
+            RecordType::HINFO => RData::HINFO {
+                cpu: Self::parse_character_string(reader)?,
+                os: Self::parse_character_string(reader)?,
+            },
+            RecordType::MINFO => RData::MINFO {
+                rmailbx: Label::parse(reader)?,
+                emailbx: Label::parse(reader)?,
+            },
+            RecordType::MX => RData::MX {
+                preference: reader.read_u16()?,
+                exchange: Label::parse(reader)?,
+            },
+            RecordType::TXT => {
+                let mut strings = vec![];
+                while reader.cur_pos < end {
+                    strings.push(Self::parse_character_string(reader)?);
+                }
+                RData::TXT(strings)
+            }
+            RecordType::OPT => RData::OPT(EdnsOption::parse_all(reader, end)?),
+            RecordType::AXFR
+            | RecordType::MAILB
+            | RecordType::MAILA
+            | RecordType::ANY
+            | RecordType::Unknown(_) => RData::Unknown(Self::read_rest(reader, end)?),
+        };
+        if reader.cur_pos != end {
+            return Err(ParseError::BadRData {
+                offset: start,
+                reason: format!("{typez} rdata does not match rd length {rd_length}"),
+            });
+        }
+        Ok(rdata)
+    }
+
+    fn parse_ipv4(reader: &mut DnsReader) -> ParseResult<Ipv4Addr> {
+        let mut octets: [u8; 4] = [0; 4];
+        reader.read_exact(&mut octets)?;
+        Ok(Ipv4Addr::from(octets))
+    }
+
+    fn parse_character_string(reader: &mut DnsReader) -> ParseResult<Vec<u8>> {
+        let len = reader.read_u8()?;
+        reader.read_vec(len as usize)
+    }
+
+    fn read_rest(reader: &mut DnsReader, end: usize) -> ParseResult<Vec<u8>> {
+        let len = end.saturating_sub(reader.cur_pos);
+        reader.read_vec(len)
+    }
+
+    fn character_string_as_bytes(buf: &mut Vec<u8>, s: &[u8]) {
+        assert!(s.len() <= 255, "character-string can't exceed 255 bytes");
+        buf.push(s.len() as u8);
+        buf.extend(s);
     }
 }
 
 impl AsBytes for RData {
     fn as_bytes(&self) -> Vec<u8> {
-        self.0
-            .split(".")
-            .map(|s| s.parse::<u8>().unwrap())
-            .collect()
+        let mut buf = vec![];
+        match self {
+            RData::A(address) => buf.extend(address.octets()),
+            RData::NS(name)
+            | RData::MD(name)
+            | RData::MF(name)
+            | RData::CNAME(name)
+            | RData::MB(name)
+            | RData::MG(name)
+            | RData::MR(name)
+            | RData::PTR(name) => buf.extend(name.as_bytes()),
+            RData::SOA {
+                mname,
+                rname,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+            } => {
+                buf.extend(mname.as_bytes());
+                buf.extend(rname.as_bytes());
+                for n in [serial, refresh, retry, expire, minimum] {
+                    buf.extend(n.to_be_bytes());
+                }
+            }
+            RData::NULL(data) | RData::Unknown(data) => buf.extend(data),
+            RData::OPT(options) => options.iter().for_each(|o| buf.extend(o.as_bytes())),
+            RData::WKS {
+                address,
+                protocol,
+                bitmap,
+            } => {
+                buf.extend(address.octets());
+                buf.push(*protocol);
+                buf.extend(bitmap);
+            }
+            RData::HINFO { cpu, os } => {
+                Self::character_string_as_bytes(&mut buf, cpu);
+                Self::character_string_as_bytes(&mut buf, os);
+            }
+            RData::MINFO { rmailbx, emailbx } => {
+                buf.extend(rmailbx.as_bytes());
+                buf.extend(emailbx.as_bytes());
+            }
+            RData::MX {
+                preference,
+                exchange,
+            } => {
+                buf.extend(preference.to_be_bytes());
+                buf.extend(exchange.as_bytes());
+            }
+            RData::TXT(strings) => strings
+                .iter()
+                .for_each(|s| Self::character_string_as_bytes(&mut buf, s)),
+        }
+        buf
     }
 }
 
+/// Only names in the RDATA of well-known types are compressed, other types may be relayed by
+/// servers that can't decompress them https://www.rfc-editor.org/rfc/rfc3597#section-4
+impl WriteTo for RData {
+    fn write_to(&self, writer: &mut DnsWriter) {
+        match self {
+            RData::NS(name) | RData::CNAME(name) | RData::PTR(name) => writer.write_label(name),
+            RData::MX {
+                preference,
+                exchange,
+            } => {
+                writer.write_u16(*preference);
+                writer.write_label(exchange);
+            }
+            RData::SOA {
+                mname,
+                rname,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+            } => {
+                writer.write_label(mname);
+                writer.write_label(rname);
+                for n in [serial, refresh, retry, expire, minimum] {
+                    writer.write_u32(*n);
+                }
+            }
+            rdata => writer.write(&rdata.as_bytes()),
+        }
+    }
+}
+
+/// Zone file presentation format
+/// https://www.rfc-editor.org/rfc/rfc1035#section-5.1
+impl Display for RData {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RData::A(address) => write!(f, "{address}"),
+            RData::NS(name)
+            | RData::MD(name)
+            | RData::MF(name)
+            | RData::CNAME(name)
+            | RData::MB(name)
+            | RData::MG(name)
+            | RData::MR(name)
+            | RData::PTR(name) => write!(f, "{name}"),
+            RData::SOA {
+                mname,
+                rname,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+            } => write!(
+                f,
+                "{mname} {rname} {serial} {refresh} {retry} {expire} {minimum}"
+            ),
+            // NULL has no presentation format, fallback to RFC 3597 generic encoding
+            RData::NULL(data) | RData::Unknown(data) => write_generic(f, data),
+            RData::OPT(_) => write_generic(f, &self.as_bytes()),
+            RData::WKS {
+                address,
+                protocol,
+                bitmap,
+            } => {
+                write!(f, "{address} {protocol}")?;
+                for (i, byte) in bitmap.iter().enumerate() {
+                    for bit in 0..8 {
+                        if byte & (0b1000_0000 >> bit) != 0 {
+                            write!(f, " {}", i * 8 + bit)?;
+                        }
+                    }
+                }
+                Ok(())
+            }
+            RData::HINFO { cpu, os } => {
+                write_character_string(f, cpu)?;
+                write!(f, " ")?;
+                write_character_string(f, os)
+            }
+            RData::MINFO { rmailbx, emailbx } => write!(f, "{rmailbx} {emailbx}"),
+            RData::MX {
+                preference,
+                exchange,
+            } => write!(f, "{preference} {exchange}"),
+            RData::TXT(strings) => {
+                for (i, s) in strings.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " ")?;
+                    }
+                    write_character_string(f, s)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Generic RDATA encoding `\# <length> <hex>`
+/// https://www.rfc-editor.org/rfc/rfc3597#section-5
+fn write_generic(f: &mut fmt::Formatter<'_>, data: &[u8]) -> fmt::Result {
+    write!(f, "\\# {}", data.len())?;
+    if !data.is_empty() {
+        write!(f, " ")?;
+    }
+    data.iter().try_for_each(|b| write!(f, "{b:02x}"))
+}
+
+/// Quoted <character-string>, non printable bytes are written as \DDD
+fn write_character_string(f: &mut fmt::Formatter<'_>, s: &[u8]) -> fmt::Result {
+    write!(f, "\"")?;
+    for &b in s {
+        match b {
+            b'"' | b'\\' => write!(f, "\\{}", b as char)?,
+            0x20..=0x7e => write!(f, "{}", b as char)?,
+            _ => write!(f, "\\{b:03}")?,
+        }
+    }
+    write!(f, "\"")
+}
+
 #[cfg(test)]
 mod tests {
+    use std::net::Ipv4Addr;
+
     use pretty_assertions::assert_eq;
 
     use crate::{
-        common::AsBytes,
+        common::{
+            dns_reader::DnsReader, dns_writer::DnsWriter, AsBytes, Parse, ParseError, WriteTo,
+        },
         dns::{label::Label, RecordClass, RecordType},
     };
 
-    use super::Answer;
+    use super::{Answer, RData};
 
     #[test]
     fn test_dns_answer() {
@@ -100,7 +432,7 @@ mod tests {
             typez: RecordType::A,
             class: RecordClass::IN,
             ttl: 60,
-            rdata: super::RData("8.8.8.8".to_string()),
+            rdata: RData::A(Ipv4Addr::new(8, 8, 8, 8)),
         };
         assert_eq!(
             answer.as_bytes(),
@@ -116,4 +448,222 @@ mod tests {
             ]
         );
     }
+
+    fn round_trip(typez: RecordType, rdata: RData) {
+        let bytes = rdata.as_bytes();
+        let mut buf = (bytes.len() as u16).to_be_bytes().to_vec();
+        buf.extend(&bytes);
+        let mut reader = DnsReader::new(&buf);
+        assert_eq!(RData::parse(&mut reader, &typez).unwrap(), rdata);
+        assert_eq!(reader.cur_pos, buf.len());
+    }
+
+    #[test]
+    fn test_rdata_round_trip() {
+        round_trip(RecordType::A, RData::A(Ipv4Addr::new(1, 2, 3, 4)));
+        round_trip(
+            RecordType::CNAME,
+            RData::CNAME(Label("www.example.com".to_string())),
+        );
+        round_trip(
+            RecordType::NS,
+            RData::NS(Label("ns1.example.com".to_string())),
+        );
+        round_trip(
+            RecordType::PTR,
+            RData::PTR(Label("host.example".to_string())),
+        );
+        round_trip(
+            RecordType::MX,
+            RData::MX {
+                preference: 10,
+                exchange: Label("mail.example.com".to_string()),
+            },
+        );
+        round_trip(
+            RecordType::SOA,
+            RData::SOA {
+                mname: Label("ns1.example.com".to_string()),
+                rname: Label("hostmaster.example.com".to_string()),
+                serial: 2024010101,
+                refresh: 7200,
+                retry: 3600,
+                expire: 1209600,
+                minimum: 300,
+            },
+        );
+        round_trip(
+            RecordType::TXT,
+            RData::TXT(vec![b"v=spf1 -all".to_vec(), vec![]]),
+        );
+        round_trip(
+            RecordType::HINFO,
+            RData::HINFO {
+                cpu: b"INTEL-386".to_vec(),
+                os: b"UNIX".to_vec(),
+            },
+        );
+        round_trip(
+            RecordType::MINFO,
+            RData::MINFO {
+                rmailbx: Label("admin.example.com".to_string()),
+                emailbx: Label("errors.example.com".to_string()),
+            },
+        );
+        round_trip(
+            RecordType::WKS,
+            RData::WKS {
+                address: Ipv4Addr::new(10, 0, 0, 1),
+                protocol: 6,
+                bitmap: vec![0b0000_0000, 0b0000_0000, 0b0010_0000],
+            },
+        );
+        round_trip(RecordType::NULL, RData::NULL(vec![0xde, 0xad, 0xbe, 0xef]));
+        round_trip(RecordType::MB, RData::MB(Label("mb.example".to_string())));
+        round_trip(RecordType::MG, RData::MG(Label("mg.example".to_string())));
+        round_trip(RecordType::MR, RData::MR(Label("mr.example".to_string())));
+        round_trip(RecordType::MD, RData::MD(Label("md.example".to_string())));
+        round_trip(RecordType::MF, RData::MF(Label("mf.example".to_string())));
+    }
+
+    #[test]
+    fn test_parse_compressed_name_in_rdata() {
+        let buf = vec![
+            7, 101, 120, 97, 109, 112, 108, 101, 3, 99, 111, 109, 0, // example.com at 0
+            0, 9, // rd length
+            0, 10, // preference
+            4, 109, 97, 105, 108, // mail
+            192, 0, // pointer to example.com
+        ];
+        let mut reader = DnsReader::new(&buf);
+        reader.cur_pos = 13;
+        assert_eq!(
+            RData::parse(&mut reader, &RecordType::MX).unwrap(),
+            RData::MX {
+                preference: 10,
+                exchange: Label("mail.example.com".to_string())
+            }
+        );
+        assert_eq!(reader.cur_pos, buf.len());
+    }
+
+    #[test]
+    fn test_rdata_length_mismatch() {
+        // A record claiming 5 bytes of rdata
+        let buf = vec![0, 5, 1, 2, 3, 4, 5];
+        let mut reader = DnsReader::new(&buf);
+        assert_eq!(
+            RData::parse(&mut reader, &RecordType::A),
+            Err(ParseError::BadRData {
+                offset: 2,
+                reason: "A rdata does not match rd length 5".to_string()
+            })
+        );
+
+        // rd length past the end of the packet
+        let buf = vec![0, 10, 1, 2, 3, 4];
+        let mut reader = DnsReader::new(&buf);
+        assert!(matches!(
+            RData::parse(&mut reader, &RecordType::A),
+            Err(ParseError::Truncated { offset: 2, .. })
+        ));
+    }
+
+    #[test]
+    fn test_write_to_compresses_rdata() {
+        let mut writer = DnsWriter::new();
+        Answer {
+            label: Label("example.com".to_string()),
+            typez: RecordType::MX,
+            class: RecordClass::IN,
+            ttl: 60,
+            rdata: RData::MX {
+                preference: 10,
+                exchange: Label("mail.example.com".to_string()),
+            },
+        }
+        .write_to(&mut writer);
+        // TXT is not a well-known type, nothing in it is compressed
+        Answer {
+            label: Label("mail.example.com".to_string()),
+            typez: RecordType::TXT,
+            class: RecordClass::IN,
+            ttl: 60,
+            rdata: RData::TXT(vec![b"example.com".to_vec()]),
+        }
+        .write_to(&mut writer);
+        let bytes = writer.into_bytes();
+        assert_eq!(
+            bytes,
+            vec![
+                7, 101, 120, 97, 109, 112, 108, 101, 3, 99, 111, 109, 0, // example.com
+                0, 15, 0, 1, 0, 0, 0, 60, //
+                0, 9, // rd length
+                0, 10, 4, 109, 97, 105, 108, 0xC0, 0, // 10 mail + pointer
+                0xC0, 25, // mail.example.com
+                0, 16, 0, 1, 0, 0, 0, 60, //
+                0, 12, 11, 101, 120, 97, 109, 112, 108, 101, 46, 99, 111, 109,
+            ]
+        );
+        let mut reader = DnsReader::new(&bytes);
+        assert_eq!(
+            Answer::parse(&mut reader).unwrap().rdata,
+            RData::MX {
+                preference: 10,
+                exchange: Label("mail.example.com".to_string()),
+            }
+        );
+        assert_eq!(
+            Answer::parse(&mut reader).unwrap().label,
+            Label("mail.example.com".to_string())
+        );
+    }
+
+    #[test]
+    fn test_display() {
+        let mx = RData::MX {
+            preference: 5,
+            exchange: Label("mail.example.com".to_string()),
+        };
+        assert_eq!(mx.to_string(), "5 mail.example.com.");
+        let txt = RData::TXT(vec![b"hello \"world\"".to_vec(), vec![0x07]]);
+        assert_eq!(txt.to_string(), "\"hello \\\"world\\\"\" \"\\007\"");
+        let wks = RData::WKS {
+            address: Ipv4Addr::new(10, 0, 0, 1),
+            protocol: 6,
+            bitmap: vec![0, 0, 0b0010_0000],
+        };
+        assert_eq!(wks.to_string(), "10.0.0.1 6 18");
+        assert_eq!(RData::NULL(vec![0xab, 0x01]).to_string(), "\\# 2 ab01");
+    }
+
+    #[test]
+    fn test_unknown_type_pass_through() {
+        // HTTPS record (type 65) for example.com, priority 1, target ".", alpn=h2
+        let bytes = vec![
+            7, 101, 120, 97, 109, 112, 108, 101, 3, 99, 111, 109, 0, // example.com
+            0, 65, // type HTTPS
+            0, 1, // class IN
+            0, 0, 1, 44, // ttl
+            0, 10, // rd length
+            0, 1, 0, 0, 1, 0, 3, 2, 104, 50, // rdata
+        ];
+        let mut reader = DnsReader::new(&bytes);
+        let answer = Answer::parse(&mut reader).unwrap();
+        assert_eq!(answer.typez, RecordType::Unknown(65));
+        assert_eq!(
+            answer.rdata,
+            RData::Unknown(vec![0, 1, 0, 0, 1, 0, 3, 2, 104, 50])
+        );
+        assert_eq!(answer.as_bytes(), bytes);
+        assert_eq!(
+            answer.to_string(),
+            "example.com. 300 IN TYPE65 \\# 10 00010000010003026832"
+        );
+    }
+
+    #[test]
+    fn test_empty_unknown_display() {
+        assert_eq!(RData::Unknown(vec![]).to_string(), "\\# 0");
+    }
 }
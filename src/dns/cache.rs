@@ -0,0 +1,783 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Context};
+
+use crate::{
+    common::{dns_reader::DnsReader, AsBytes, Parse},
+    fdbg,
+};
+
+use super::{
+    answer::{Answer, RData},
+    edns::Edns,
+    header::{Header, QueryResponse, RCode},
+    label::Label,
+    packet::Packet,
+    question::Question,
+    RecordClass, RecordType,
+};
+
+/// Entries kept by default, the least recently used one goes first when it is full
+pub const DEFAULT_CACHE_SIZE: usize = 10_000;
+/// How long expired entries are kept to answer with while upstream is unreachable
+/// https://www.rfc-editor.org/rfc/rfc8767#section-5
+pub const DEFAULT_STALE_WINDOW: Duration = Duration::from_secs(24 * 60 * 60);
+/// TTL of stale records, clients ask again soon in case upstream is back
+const STALE_TTL: u32 = 30;
+/// Entries answered this many times are refreshed before they expire
+const PREFETCH_MIN_HITS: u32 = 3;
+/// Refresh once the remaining TTL is below 1/10 of the original
+const PREFETCH_TTL_DIVISOR: u32 = 10;
+/// First line of a saved cache, describes the lines after it
+const CACHE_FILE_HEADER: &str =
+    "# dns cache v1: <stored unix secs> <expires unix secs> <response in hex>";
+
+/// Question a response is cached for, names are compared without case. Queries with the DO
+/// bit get DNSSEC records the others don't, so they are cached apart
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct CacheKey {
+    name: String,
+    typez: u16,
+    class: u16,
+    dnssec_ok: bool,
+}
+
+impl CacheKey {
+    /// Key of a query with a single question
+    fn new(query: &Packet) -> Option<Self> {
+        let question = query.questions.first()?;
+        Some(Self {
+            name: question.name.0.trim_end_matches('.').to_ascii_lowercase(),
+            typez: question.typez.as_u16(),
+            class: question.class.as_u16(),
+            dnssec_ok: query.edns.as_ref().is_some_and(|edns| edns.dnssec_ok),
+        })
+    }
+}
+
+struct Entry {
+    rcode: RCode,
+    answers: Vec<Answer>,
+    authorities: Vec<Answer>,
+    additionals: Vec<Answer>,
+    stored: Instant,
+    expires: Instant,
+    /// Position in `CacheEntries::lru`
+    used: u64,
+    hits: u32,
+    /// Set once a refresh is running, the refreshed entry replaces this one
+    prefetching: bool,
+}
+
+#[derive(Default)]
+struct CacheEntries {
+    entries: HashMap<CacheKey, Entry>,
+    /// Keys from least to most recently used
+    lru: BTreeMap<u64, CacheKey>,
+    next_use: u64,
+}
+
+impl CacheEntries {
+    fn next_use(&mut self) -> u64 {
+        self.next_use += 1;
+        self.next_use
+    }
+
+    fn touch(&mut self, key: &CacheKey) {
+        let used = self.next_use();
+        if let Some(entry) = self.entries.get_mut(key) {
+            self.lru.remove(&entry.used);
+            entry.used = used;
+            self.lru.insert(used, key.clone());
+        }
+    }
+
+    fn remove(&mut self, key: &CacheKey) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.lru.remove(&entry.used);
+        }
+    }
+}
+
+/// Upstream responses by question, shared by every worker thread. Entries live as long as the
+/// shortest TTL in them and are served with the TTLs counted down
+pub struct DnsCache {
+    capacity: usize,
+    stale_window: Duration,
+    entries: Mutex<CacheEntries>,
+}
+
+impl DnsCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            stale_window: Duration::ZERO,
+            entries: Mutex::default(),
+        }
+    }
+
+    /// Keeps entries this long past their expiry for [`DnsCache::get_stale`]
+    pub fn stale_window(mut self, stale_window: Duration) -> Self {
+        self.stale_window = stale_window;
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.lock().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Response to a query with a single question, None when it is not cached or has expired
+    pub fn get(&self, query: &Packet, now: Instant) -> Option<Packet> {
+        self.lookup(query, now, false)
+    }
+
+    /// Like [`DnsCache::get`], but expired responses within the stale window are returned as
+    /// well with a short TTL. Only meant for when upstream can't answer
+    pub fn get_stale(&self, query: &Packet, now: Instant) -> Option<Packet> {
+        self.lookup(query, now, true)
+    }
+
+    /// Whether the response is popular and about to expire. True only once per entry, so a
+    /// single refresh runs for it
+    pub fn claim_prefetch(&self, query: &Packet, now: Instant) -> bool {
+        let Some(key) = CacheKey::new(query) else {
+            return false;
+        };
+        let mut entries = self.lock();
+        let Some(entry) = entries.entries.get_mut(&key) else {
+            return false;
+        };
+        if entry.prefetching || entry.hits < PREFETCH_MIN_HITS || entry.expires <= now {
+            return false;
+        }
+        let ttl = entry.expires - entry.stored;
+        if entry.expires - now > ttl / PREFETCH_TTL_DIVISOR {
+            return false;
+        }
+        entry.prefetching = true;
+        true
+    }
+
+    /// Lets [`DnsCache::claim_prefetch`] return true again for the entry, once its refresh is
+    /// done or did not happen
+    pub fn release_prefetch(&self, query: &Packet) {
+        let Some(key) = CacheKey::new(query) else {
+            return;
+        };
+        if let Some(entry) = self.lock().entries.get_mut(&key) {
+            entry.prefetching = false;
+        }
+    }
+
+    fn lookup(&self, query: &Packet, now: Instant, stale: bool) -> Option<Packet> {
+        let key = CacheKey::new(query)?;
+        let mut entries = self.lock();
+        let entry = entries.entries.get_mut(&key)?;
+        if entry.expires + self.stale_window <= now {
+            entries.remove(&key);
+            return None;
+        }
+        let expired = entry.expires <= now;
+        if expired && !stale {
+            return None;
+        }
+        entry.hits = entry.hits.saturating_add(1);
+        let elapsed = now.duration_since(entry.stored).as_secs() as u32;
+        let age = |records: &[Answer]| {
+            records
+                .iter()
+                .map(|record| Answer {
+                    ttl: if expired {
+                        STALE_TTL
+                    } else {
+                        record.ttl.saturating_sub(elapsed)
+                    },
+                    ..record.clone()
+                })
+                .collect()
+        };
+        let response = Packet::builder()
+            .header(Header {
+                qr: QueryResponse::Reply,
+                rcode: entry.rcode,
+                ..query.header.clone()
+            })
+            .questions(query.questions.clone())
+            .answers(age(&entry.answers))
+            .authorities(age(&entry.authorities))
+            .additionals(age(&entry.additionals))
+            .build();
+        entries.touch(&key);
+        Some(response)
+    }
+
+    /// Keeps the response to a single question query when it can be reused, i.e. it answers
+    /// that question and has answers or it is NXDOMAIN or NODATA with the SOA of the zone.
+    /// Returns whether it was kept
+    pub fn insert(&self, query: &Packet, response: &Packet, now: Instant) -> bool {
+        let Some(key) = CacheKey::new(query) else {
+            return false;
+        };
+        if response.questions.len() != 1 || !response.questions[0].matches(&query.questions[0]) {
+            return false;
+        }
+        let Some((ttl, authorities)) = Self::cacheable(response) else {
+            return false;
+        };
+        if ttl == 0 {
+            return false;
+        }
+
+        let mut entries = self.lock();
+        entries.remove(&key);
+        while entries.entries.len() >= self.capacity {
+            let Some((_, oldest)) = entries.lru.pop_first() else {
+                break;
+            };
+            entries.entries.remove(&oldest);
+        }
+        let used = entries.next_use();
+        entries.lru.insert(used, key.clone());
+        entries.entries.insert(
+            key,
+            Entry {
+                rcode: response.header.rcode,
+                answers: response.answers.clone(),
+                authorities,
+                additionals: response.additionals.clone(),
+                stored: now,
+                expires: now + Duration::from_secs(ttl as u64),
+                used,
+                hits: 0,
+                prefetching: false,
+            },
+        );
+        true
+    }
+
+    /// How long the response can be kept along with the authority records to keep
+    fn cacheable(response: &Packet) -> Option<(u32, Vec<Answer>)> {
+        let min_ttl = |records: &[Answer]| records.iter().map(|record| record.ttl).min();
+        match response.header.rcode {
+            RCode::NoError if !response.answers.is_empty() => {
+                let ttl = [
+                    min_ttl(&response.answers),
+                    min_ttl(&response.authorities),
+                    min_ttl(&response.additionals),
+                ]
+                .into_iter()
+                .flatten()
+                .min()?;
+                Some((ttl, response.authorities.clone()))
+            }
+            // negative answers are kept for the SOA TTL capped by its minimum field and served
+            // with the SOA https://www.rfc-editor.org/rfc/rfc2308#section-5
+            RCode::NoError | RCode::NXDomain => {
+                let soa = response
+                    .authorities
+                    .iter()
+                    .find_map(|record| match record.rdata {
+                        RData::SOA { minimum, .. } => Some(Answer {
+                            ttl: record.ttl.min(minimum),
+                            ..record.clone()
+                        }),
+                        _ => None,
+                    })?;
+                // a CNAME may lead to the name that does not exist
+                let ttl = min_ttl(&response.answers).map_or(soa.ttl, |ttl| ttl.min(soa.ttl));
+                Some((ttl, vec![soa]))
+            }
+            _ => None,
+        }
+    }
+
+    /// Writes the entries that have not expired with absolute times, so the next run knows how
+    /// much of their TTL is left. Returns how many were written
+    pub fn save(&self, path: &Path) -> anyhow::Result<usize> {
+        let (text, count) = self.dump(Instant::now(), SystemTime::now());
+        // stopping half way through must not leave a truncated cache behind
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let tmp = PathBuf::from(tmp);
+        std::fs::write(&tmp, text).context(fdbg!("Unable to write {}", tmp.display()))?;
+        std::fs::rename(&tmp, path).context(fdbg!("Unable to replace {}", path.display()))?;
+        Ok(count)
+    }
+
+    /// Adds the entries written by [`DnsCache::save`] that have not expired since.
+    /// Returns how many were added
+    pub fn load(&self, path: &Path) -> anyhow::Result<usize> {
+        let text =
+            std::fs::read_to_string(path).context(fdbg!("Unable to read {}", path.display()))?;
+        self.restore(&text, Instant::now(), SystemTime::now())
+            .with_context(|| format!("Invalid cache file {}", path.display()))
+    }
+
+    fn dump(&self, now: Instant, wall: SystemTime) -> (String, usize) {
+        let entries = self.lock();
+        let mut text = format!("{CACHE_FILE_HEADER}\n");
+        let mut count = 0;
+        // least recently used first, loading them in this order keeps the order
+        for key in entries.lru.values() {
+            let entry = &entries.entries[key];
+            if entry.expires <= now {
+                continue;
+            }
+            let (Some(stored), Some(expires)) = (
+                unix_secs(entry.stored, now, wall),
+                unix_secs(entry.expires, now, wall),
+            ) else {
+                continue;
+            };
+            let response = Packet::builder()
+                .header(Header {
+                    qr: QueryResponse::Reply,
+                    rcode: entry.rcode,
+                    ..Default::default()
+                })
+                .question(Question {
+                    name: Label(key.name.clone()),
+                    typez: RecordType::from_u16(key.typez),
+                    class: RecordClass::from_u16(key.class),
+                })
+                // the DO bit of the key goes along in the OPT record
+                .edns(key.dnssec_ok.then(|| Edns {
+                    dnssec_ok: true,
+                    ..Default::default()
+                }))
+                .answers(entry.answers.clone())
+                .authorities(entry.authorities.clone())
+                .additionals(entry.additionals.clone())
+                .build();
+            let hex = response
+                .as_bytes()
+                .iter()
+                .map(|b| format!("{b:02x}"))
+                .collect::<String>();
+            text.push_str(&format!("{stored} {expires} {hex}\n"));
+            count += 1;
+        }
+        (text, count)
+    }
+
+    fn restore(&self, text: &str, now: Instant, wall: SystemTime) -> anyhow::Result<usize> {
+        let wall = wall.duration_since(UNIX_EPOCH)?.as_secs();
+        let mut count = 0;
+        for (i, line) in text.lines().enumerate() {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (stored, expires, response) =
+                parse_line(line).with_context(|| format!("line {}", i + 1))?;
+            if expires <= wall {
+                continue;
+            }
+            // TTLs count down from when the response was stored
+            let age = wall.saturating_sub(stored).min(u32::MAX as u64) as u32;
+            let age = |records: Vec<Answer>| {
+                records
+                    .into_iter()
+                    .map(|record| Answer {
+                        ttl: record.ttl.saturating_sub(age),
+                        ..record
+                    })
+                    .collect()
+            };
+            let response = Packet::builder()
+                .header(response.header)
+                .questions(response.questions)
+                .edns(response.edns)
+                .answers(age(response.answers))
+                .authorities(age(response.authorities))
+                .additionals(age(response.additionals))
+                .build();
+            if self.insert(&response, &response, now) {
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, CacheEntries> {
+        self.entries.lock().expect("Cache lock is poisoned")
+    }
+}
+
+/// `instant` as seconds since the Unix epoch, given what both clocks say now
+fn unix_secs(instant: Instant, now: Instant, wall: SystemTime) -> Option<u64> {
+    let wall = match instant.checked_duration_since(now) {
+        Some(ahead) => wall.checked_add(ahead)?,
+        None => wall.checked_sub(now.duration_since(instant))?,
+    };
+    Some(wall.duration_since(UNIX_EPOCH).ok()?.as_secs())
+}
+
+fn parse_line(line: &str) -> anyhow::Result<(u64, u64, Packet)> {
+    let mut fields = line.split(' ');
+    let mut next = |name: &str| fields.next().ok_or_else(|| anyhow!("missing {name}"));
+    let stored = next("stored time")?.parse::<u64>()?;
+    let expires = next("expiry time")?.parse::<u64>()?;
+    let hex = next("response")?;
+    if hex.len() % 2 != 0 {
+        return Err(anyhow!("response has an odd number of hex digits"));
+    }
+    let digit = |b: &u8| (*b as char).to_digit(16).filter(|_| b.is_ascii_hexdigit());
+    let bytes = hex
+        .as_bytes()
+        .chunks(2)
+        .map(|pair| match (digit(&pair[0]), digit(&pair[1])) {
+            (Some(high), Some(low)) => Ok((high << 4 | low) as u8),
+            _ => Err(anyhow!("response has a character that is not a hex digit")),
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let response = Packet::parse(&mut DnsReader::new(&bytes))?;
+    Ok((stored, expires, response))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::Ipv4Addr,
+        time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+    };
+
+    use pretty_assertions::assert_eq;
+
+    use crate::{
+        common::AsBytes,
+        dns::{
+            answer::{Answer, RData},
+            edns::Edns,
+            header::{Header, QueryResponse, RCode},
+            label::Label,
+            packet::Packet,
+            question::Question,
+            RecordClass, RecordType,
+        },
+    };
+
+    use super::DnsCache;
+
+    fn query(name: &str) -> Packet {
+        Packet::builder()
+            .header(Header {
+                id: 7,
+                ..Default::default()
+            })
+            .question(Question {
+                name: Label(name.to_string()),
+                typez: RecordType::A,
+                class: RecordClass::IN,
+            })
+            .build()
+    }
+
+    fn response(name: &str, ttl: u32) -> Packet {
+        Packet::builder()
+            .header(Header {
+                qr: QueryResponse::Reply,
+                ..Default::default()
+            })
+            .questions(query(name).questions)
+            .answer(Answer {
+                label: Label(name.to_string()),
+                typez: RecordType::A,
+                class: RecordClass::IN,
+                ttl,
+                rdata: RData::A(Ipv4Addr::new(10, 0, 0, 1)),
+            })
+            .build()
+    }
+
+    #[test]
+    fn test_ttl_counts_down_until_expired() {
+        let cache = DnsCache::new(10);
+        let now = Instant::now();
+        cache.insert(&query("example.com"), &response("example.com", 60), now);
+
+        let cached = cache
+            .get(&query("Example.COM."), now + Duration::from_secs(20))
+            .unwrap();
+        assert_eq!(cached.header.id, 7);
+        assert_eq!(cached.header.qr, QueryResponse::Reply);
+        assert_eq!(cached.header.ancount, 1);
+        assert_eq!(cached.answers[0].ttl, 40);
+
+        assert!(cache
+            .get(&query("example.com"), now + Duration::from_secs(60))
+            .is_none());
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn test_least_recently_used_is_evicted() {
+        let cache = DnsCache::new(2);
+        let now = Instant::now();
+        cache.insert(&query("a.com"), &response("a.com", 60), now);
+        cache.insert(&query("b.com"), &response("b.com", 60), now);
+        assert!(cache.get(&query("a.com"), now).is_some());
+        cache.insert(&query("c.com"), &response("c.com", 60), now);
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get(&query("b.com"), now).is_none());
+        assert!(cache.get(&query("a.com"), now).is_some());
+        assert!(cache.get(&query("c.com"), now).is_some());
+    }
+
+    #[test]
+    fn test_stale_answers_within_window() {
+        let cache = DnsCache::new(10).stale_window(Duration::from_secs(3600));
+        let now = Instant::now();
+        cache.insert(&query("example.com"), &response("example.com", 60), now);
+
+        let expired = now + Duration::from_secs(120);
+        assert!(cache.get(&query("example.com"), expired).is_none());
+        let stale = cache.get_stale(&query("example.com"), expired).unwrap();
+        assert_eq!(stale.answers[0].ttl, 30);
+        // fresh entries come back as they are
+        let fresh = cache
+            .get_stale(&query("example.com"), now + Duration::from_secs(10))
+            .unwrap();
+        assert_eq!(fresh.answers[0].ttl, 50);
+
+        assert!(cache
+            .get_stale(&query("example.com"), now + Duration::from_secs(3660))
+            .is_none());
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn test_prefetch_popular_entries_near_expiry() {
+        let cache = DnsCache::new(10);
+        let now = Instant::now();
+        cache.insert(&query("example.com"), &response("example.com", 100), now);
+        let near_expiry = now + Duration::from_secs(95);
+        for _ in 0..3 {
+            assert!(!cache.claim_prefetch(&query("example.com"), near_expiry));
+            cache.get(&query("example.com"), near_expiry).unwrap();
+        }
+        assert!(!cache.claim_prefetch(&query("example.com"), now + Duration::from_secs(50)));
+        assert!(cache.claim_prefetch(&query("example.com"), near_expiry));
+        // one refresh is enough
+        assert!(!cache.claim_prefetch(&query("example.com"), near_expiry));
+        // unless it failed
+        cache.release_prefetch(&query("example.com"));
+        assert!(cache.claim_prefetch(&query("example.com"), near_expiry));
+
+        // the refreshed entry starts over
+        cache.insert(
+            &query("example.com"),
+            &response("example.com", 100),
+            near_expiry,
+        );
+        assert!(!cache.claim_prefetch(&query("example.com"), near_expiry + Duration::from_secs(95)));
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let cache = DnsCache::new(10);
+        let now = Instant::now();
+        let wall = SystemTime::now();
+        cache.insert(&query("a.com"), &response("a.com", 60), now);
+        cache.insert(&query("b.com"), &response("b.com", 600), now);
+        cache.insert(
+            &query("typo.com"),
+            &negative("typo.com", RCode::NXDomain, 900, 300),
+            now,
+        );
+        let (text, count) = cache.dump(now, wall);
+        assert_eq!(count, 3);
+
+        // restarted 2 minutes later, a.com has expired meanwhile
+        let restarted = DnsCache::new(10);
+        let later = Instant::now();
+        let count = restarted
+            .restore(&text, later, wall + Duration::from_secs(120))
+            .unwrap();
+        assert_eq!(count, 2);
+        assert!(restarted.get(&query("a.com"), later).is_none());
+        let cached = restarted.get(&query("B.com"), later).unwrap();
+        assert_eq!(cached.answers[0].ttl, 480);
+        assert_eq!(
+            cached.answers[0].rdata,
+            RData::A(Ipv4Addr::new(10, 0, 0, 1))
+        );
+        let cached = restarted.get(&query("typo.com"), later).unwrap();
+        assert_eq!(cached.header.rcode, RCode::NXDomain);
+        assert_eq!(cached.authorities[0].ttl, 180);
+        assert!(restarted
+            .get(&query("b.com"), later + Duration::from_secs(480))
+            .is_none());
+
+        let error = restarted.restore("1 2 0f", later, wall).unwrap_err();
+        assert!(format!("{error:#}").starts_with("line 1: "));
+        // multi-byte characters must not split the hex digit pairs
+        let error = restarted.restore("1 2 aéb", later, wall).unwrap_err();
+        assert_eq!(
+            format!("{error:#}"),
+            "line 1: response has a character that is not a hex digit"
+        );
+    }
+
+    fn negative(name: &str, rcode: RCode, soa_ttl: u32, minimum: u32) -> Packet {
+        Packet::builder()
+            .header(Header {
+                qr: QueryResponse::Reply,
+                rcode,
+                ..Default::default()
+            })
+            .questions(query(name).questions)
+            .authority(Answer {
+                label: Label("com".to_string()),
+                typez: RecordType::SOA,
+                class: RecordClass::IN,
+                ttl: soa_ttl,
+                rdata: RData::SOA {
+                    mname: Label("a.gtld-servers.net".to_string()),
+                    rname: Label("nstld.verisign-grs.com".to_string()),
+                    serial: 1,
+                    refresh: 1800,
+                    retry: 900,
+                    expire: 604800,
+                    minimum,
+                },
+            })
+            .build()
+    }
+
+    #[test]
+    fn test_negative_answers_are_cached_with_soa() {
+        let cache = DnsCache::new(10);
+        let now = Instant::now();
+        cache.insert(
+            &query("typo.com"),
+            &negative("typo.com", RCode::NXDomain, 900, 60),
+            now,
+        );
+        cache.insert(
+            &query("nodata.com"),
+            &negative("nodata.com", RCode::NoError, 30, 300),
+            now,
+        );
+
+        let cached = cache
+            .get(&query("typo.com"), now + Duration::from_secs(10))
+            .unwrap();
+        assert_eq!(cached.header.rcode, RCode::NXDomain);
+        assert_eq!(cached.header.nscount, 1);
+        assert_eq!(cached.authorities[0].typez, RecordType::SOA);
+        assert_eq!(cached.authorities[0].ttl, 50);
+        assert!(cache
+            .get(&query("typo.com"), now + Duration::from_secs(60))
+            .is_none());
+
+        let cached = cache
+            .get(&query("nodata.com"), now + Duration::from_secs(10))
+            .unwrap();
+        assert_eq!(cached.header.rcode, RCode::NoError);
+        assert_eq!(cached.header.ancount, 0);
+        assert_eq!(cached.authorities[0].ttl, 20);
+    }
+
+    #[test]
+    fn test_uncacheable_responses() {
+        let cache = DnsCache::new(10);
+        let now = Instant::now();
+        cache.insert(&query("a.com"), &response("a.com", 0), now);
+        let mut failed = response("b.com", 60);
+        failed.header.rcode = RCode::ServFail;
+        cache.insert(&query("b.com"), &failed, now);
+        // negative answers without a SOA say nothing about how long they hold
+        let mut nxdomain = Packet::builder()
+            .questions(query("c.com").questions)
+            .build();
+        nxdomain.header.rcode = RCode::NXDomain;
+        cache.insert(&query("c.com"), &nxdomain, now);
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn test_response_must_answer_the_query() {
+        let cache = DnsCache::new(10);
+        let now = Instant::now();
+        cache.insert(&query("a.com"), &response("evil.com", 60), now);
+        let mut mx = response("a.com", 60);
+        mx.questions[0].typez = RecordType::MX;
+        cache.insert(&query("a.com"), &mx, now);
+        let mut unasked = response("a.com", 60);
+        unasked.questions.clear();
+        cache.insert(&query("a.com"), &unasked, now);
+        assert!(cache.is_empty());
+
+        cache.insert(&query("a.com"), &response("A.com.", 60), now);
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[test]
+    fn test_dnssec_ok_is_cached_apart() {
+        let cache = DnsCache::new(10);
+        let now = Instant::now();
+        let mut dnssec = query("a.com");
+        dnssec.edns = Some(Edns {
+            dnssec_ok: true,
+            ..Default::default()
+        });
+        cache.insert(&dnssec, &response("a.com", 60), now);
+        assert!(cache.get(&query("a.com"), now).is_none());
+        assert!(cache.get(&dnssec, now).is_some());
+
+        // saved and loaded under the same key
+        let wall = SystemTime::now();
+        let (text, _) = cache.dump(now, wall);
+        let restarted = DnsCache::new(10);
+        assert_eq!(restarted.restore(&text, now, wall).unwrap(), 1);
+        assert!(restarted.get(&query("a.com"), now).is_none());
+        assert!(restarted.get(&dnssec, now).is_some());
+    }
+
+    #[test]
+    fn test_restore_counts_kept_entries() {
+        let wall = SystemTime::now();
+        let secs = wall.duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let mut failed = response("a.com", 60);
+        failed.header.rcode = RCode::ServFail;
+        let hex = |packet: &Packet| {
+            packet
+                .as_bytes()
+                .iter()
+                .map(|b| format!("{b:02x}"))
+                .collect::<String>()
+        };
+        let text = format!(
+            "{secs} {} {}\n{secs} {} {}\n",
+            secs + 60,
+            hex(&response("b.com", 60)),
+            secs + 60,
+            hex(&failed)
+        );
+        let cache = DnsCache::new(10);
+        assert_eq!(cache.restore(&text, Instant::now(), wall).unwrap(), 1);
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[test]
+    fn test_save_to_tmp_path() {
+        let dir = std::env::temp_dir().join(format!("dns-cache-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("cache.tmp");
+        let cache = DnsCache::new(10);
+        cache.insert(&query("a.com"), &response("a.com", 60), Instant::now());
+        assert_eq!(cache.save(&path).unwrap(), 1);
+        assert!(!dir.join("cache.tmp.tmp").exists());
+        assert_eq!(DnsCache::new(10).load(&path).unwrap(), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
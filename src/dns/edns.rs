@@ -0,0 +1,201 @@
+use crate::common::{dns_reader::DnsReader, AsBytes, ParseError, ParseResult};
+
+use super::{
+    answer::{Answer, RData},
+    header::RCode,
+    label::Label,
+    RecordClass, RecordType,
+};
+
+/// UDP payload size we advertise and are ready to receive, small enough to avoid IP fragmentation
+/// https://www.dnsflagday.net/2020/
+pub const UDP_PAYLOAD_SIZE: u16 = 1232;
+/// Without EDNS a UDP message is limited to 512 bytes
+/// https://www.rfc-editor.org/rfc/rfc1035#section-2.3.4
+pub const MIN_UDP_PAYLOAD_SIZE: u16 = 512;
+/// The only EDNS version there is
+pub const EDNS_VERSION: u8 = 0;
+
+/// EDNS(0) information carried in the OPT pseudo-record of the additional section
+/// https://www.rfc-editor.org/rfc/rfc6891#section-6.1.2
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Edns {
+    /// Largest UDP payload the sender is able to receive
+    pub udp_payload_size: u16,
+    pub version: u8,
+    /// DNSSEC OK (DO) bit
+    pub dnssec_ok: bool,
+    pub options: Vec<EdnsOption>,
+}
+
+impl Default for Edns {
+    fn default() -> Self {
+        Self {
+            udp_payload_size: UDP_PAYLOAD_SIZE,
+            version: EDNS_VERSION,
+            dnssec_ok: false,
+            options: vec![],
+        }
+    }
+}
+
+/// {attribute, value} pair in the OPT RDATA
+/// https://www.rfc-editor.org/rfc/rfc6891#section-6.1.2
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EdnsOption {
+    pub code: u16,
+    pub data: Vec<u8>,
+}
+
+impl EdnsOption {
+    /// Options take all of the RDATA, so they are read until `end`
+    pub fn parse_all(reader: &mut DnsReader, end: usize) -> ParseResult<Vec<Self>> {
+        let mut options = vec![];
+        while reader.cur_pos < end {
+            let code = reader.read_u16()?;
+            let len = reader.read_u16()?;
+            let data = reader.read_vec(len as usize)?;
+            options.push(EdnsOption { code, data });
+        }
+        Ok(options)
+    }
+}
+
+impl AsBytes for EdnsOption {
+    fn as_bytes(&self) -> Vec<u8> {
+        let mut buf = self.code.to_be_bytes().to_vec();
+        buf.extend((self.data.len() as u16).to_be_bytes());
+        buf.extend(&self.data);
+        buf
+    }
+}
+
+impl Edns {
+    /// Read the OPT record found at `offset`, the upper 8 bits of the extended RCODE are returned
+    /// alongside as they belong to the header
+    pub fn from_answer(answer: Answer, offset: usize) -> ParseResult<(Self, u8)> {
+        if !answer.label.0.is_empty() {
+            return Err(ParseError::BadRData {
+                offset,
+                reason: format!("OPT record owner must be the root, got {}", answer.label),
+            });
+        }
+        let RData::OPT(options) = answer.rdata else {
+            return Err(ParseError::BadRData {
+                offset,
+                reason: "OPT record without OPT rdata".to_string(),
+            });
+        };
+        let [extended_rcode, version, flags, _] = answer.ttl.to_be_bytes();
+        Ok((
+            Self {
+                udp_payload_size: answer.class.as_u16(),
+                version,
+                dnssec_ok: flags & 0b1000_0000 != 0,
+                options,
+            },
+            extended_rcode,
+        ))
+    }
+
+    /// OPT pseudo-record for this EDNS, `rcode` provides the upper 8 bits of the extended RCODE
+    pub fn as_answer(&self, rcode: RCode) -> Answer {
+        let extended_rcode = (rcode.as_u16() >> 4) as u8;
+        let flags = if self.dnssec_ok { 0b1000_0000 } else { 0 };
+        Answer {
+            label: Label("".to_string()),
+            typez: RecordType::OPT,
+            class: RecordClass::from_u16(self.udp_payload_size),
+            ttl: u32::from_be_bytes([extended_rcode, self.version, flags, 0]),
+            rdata: RData::OPT(self.options.clone()),
+        }
+    }
+
+    /// Our EDNS for a reply to a query carrying this EDNS, only the DO bit is copied over
+    /// https://www.rfc-editor.org/rfc/rfc3225#section-3
+    pub fn reply(&self) -> Edns {
+        Edns {
+            dnssec_ok: self.dnssec_ok,
+            ..Default::default()
+        }
+    }
+
+    /// Size we can use for a reply to a query carrying this EDNS, anything below 512 is
+    /// treated as 512 and anything above what we are ready to send is capped
+    pub fn negotiated_payload_size(&self) -> u16 {
+        self.udp_payload_size
+            .clamp(MIN_UDP_PAYLOAD_SIZE, UDP_PAYLOAD_SIZE)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use crate::{
+        common::AsBytes,
+        dns::{header::RCode, RecordType},
+    };
+
+    use super::{Edns, EdnsOption, UDP_PAYLOAD_SIZE};
+
+    #[test]
+    fn test_as_answer() {
+        let edns = Edns {
+            udp_payload_size: 4096,
+            version: 0,
+            dnssec_ok: true,
+            options: vec![EdnsOption {
+                code: 10,
+                data: vec![1, 2, 3, 4, 5, 6, 7, 8],
+            }],
+        };
+        let answer = edns.as_answer(RCode::BadVers);
+        assert_eq!(answer.typez, RecordType::OPT);
+        assert_eq!(
+            answer.as_bytes(),
+            vec![
+                0, // root
+                0,
+                41, // type OPT
+                16,
+                0, // udp payload size 4096
+                1,
+                0,
+                0b1000_0000,
+                0, // extended rcode, version, DO, z
+                0,
+                12, // rd length
+                0,
+                10,
+                0,
+                8,
+                1,
+                2,
+                3,
+                4,
+                5,
+                6,
+                7,
+                8, // cookie option
+            ]
+        );
+        let (parsed, extended_rcode) = Edns::from_answer(answer, 0).unwrap();
+        assert_eq!(parsed, edns);
+        assert_eq!(extended_rcode, 1);
+    }
+
+    #[test]
+    fn test_negotiated_payload_size() {
+        let size = |udp_payload_size| {
+            Edns {
+                udp_payload_size,
+                ..Default::default()
+            }
+            .negotiated_payload_size()
+        };
+        assert_eq!(size(0), 512);
+        assert_eq!(size(1000), 1000);
+        assert_eq!(size(65535), UDP_PAYLOAD_SIZE);
+    }
+}
@@ -6,7 +6,9 @@ use QueryResponse::Reply;
 use crate::dns::header::QueryResponse::Question;
 use crate::{
     bits, bits16,
-    common::{binary_macros::push_bits, dns_reader::DnsReader, AsBytes, Parse},
+    common::{
+        binary_macros::push_bits, dns_reader::DnsReader, AsBytes, Parse, ParseError, ParseResult,
+    },
 };
 
 /// DnsHeader represents the header of a DNS packet.
@@ -30,7 +32,7 @@ pub struct Header {
     /// Z          3 bits     Reserved (Z)
     pub z: u8,
     /// RCODE      4 bits     Response Code (RCOODE)
-    pub rcode: u8,
+    pub rcode: RCode,
     /// QDCOUNT    16 bits    Question Count (QDCOUNT)
     pub qdcount: u16,
     /// ANCOUNT    16 bits    Answer Record Count (ANCOUNT)
@@ -41,18 +43,14 @@ pub struct Header {
     pub arcount: u16,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Default, Debug, Clone, PartialEq)]
 pub enum OpCode {
+    #[default]
     Query,
     IQuery,
     Status,
     Reserved(u8),
 }
-impl Default for OpCode {
-    fn default() -> Self {
-        return OpCode::Query;
-    }
-}
 impl OpCode {
     pub fn from_u8(value: u8) -> Self {
         use OpCode::*;
@@ -74,22 +72,84 @@ impl OpCode {
     }
 }
 
-#[derive(Debug, Clone, PartialEq)]
+/// https://www.rfc-editor.org/rfc/rfc1035#section-4.1.1
+/// https://www.rfc-editor.org/rfc/rfc2136#section-2.2
+/// Values above 15 only fit with the upper 8 bits carried in the OPT record
+/// https://www.rfc-editor.org/rfc/rfc6891#section-6.1.3
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RCode {
+    #[default]
+    NoError,
+    /// The name server was unable to interpret the query
+    FormErr,
+    /// The name server was unable to process this query due to a problem with the name server
+    ServFail,
+    /// The domain name referenced in the query does not exist
+    NXDomain,
+    /// The name server does not support the requested kind of query
+    NotImp,
+    /// The name server refuses to perform the specified operation for policy reasons
+    Refused,
+    YXDomain,
+    YXRRSet,
+    NXRRSet,
+    NotAuth,
+    NotZone,
+    /// The EDNS version of the query is not supported
+    BadVers,
+    Reserved(u16),
+}
+impl RCode {
+    pub fn from_u16(value: u16) -> Self {
+        use RCode::*;
+        match value {
+            0 => NoError,
+            1 => FormErr,
+            2 => ServFail,
+            3 => NXDomain,
+            4 => NotImp,
+            5 => Refused,
+            6 => YXDomain,
+            7 => YXRRSet,
+            8 => NXRRSet,
+            9 => NotAuth,
+            10 => NotZone,
+            16 => BadVers,
+            _ => Reserved(value),
+        }
+    }
+    pub fn as_u16(&self) -> u16 {
+        use RCode::*;
+        match self {
+            NoError => 0,
+            FormErr => 1,
+            ServFail => 2,
+            NXDomain => 3,
+            NotImp => 4,
+            Refused => 5,
+            YXDomain => 6,
+            YXRRSet => 7,
+            NXRRSet => 8,
+            NotAuth => 9,
+            NotZone => 10,
+            BadVers => 16,
+            Reserved(value) => *value,
+        }
+    }
+}
+
+#[derive(Default, Debug, Clone, PartialEq)]
 pub enum QueryResponse {
+    #[default]
     Question,
     Reply,
 }
-impl Default for QueryResponse {
-    fn default() -> Self {
-        return Question;
-    }
-}
 impl QueryResponse {
-    pub fn from_u8(value: u8) -> Self {
+    pub fn from_u8(value: u8) -> Option<Self> {
         match value {
-            0 => Question,
-            1 => Reply,
-            _ => panic!("Invalid value for QueryResponse"),
+            0 => Some(Question),
+            1 => Some(Reply),
+            _ => None,
         }
     }
     pub fn as_u8(&self) -> u8 {
@@ -109,86 +169,88 @@ impl AsBytes for Header {
         bytes[6..8].copy_from_slice(&self.ancount.to_be_bytes());
         bytes[8..10].copy_from_slice(&self.nscount.to_be_bytes());
         bytes[10..12].copy_from_slice(&self.arcount.to_be_bytes());
-        return bytes.to_vec();
+        bytes.to_vec()
     }
 }
 impl Parse for Header {
-    fn parse(reader: &mut DnsReader) -> Self {
-        let mut header = Header::default()
-            .read_id(reader)
-            .read_flags(reader)
-            .read_qd_count(reader)
-            .read_an_count(reader)
-            .read_ns_count(reader)
-            .read_ar_count(reader);
-        header
+    fn parse(reader: &mut DnsReader) -> ParseResult<Self> {
+        Header::default()
+            .read_id(reader)?
+            .read_flags(reader)?
+            .read_qd_count(reader)?
+            .read_an_count(reader)?
+            .read_ns_count(reader)?
+            .read_ar_count(reader)
     }
 }
 
 impl Header {
-    fn read_id(mut self, reader: &mut DnsReader) -> Self {
-        let mut buf: [u8; 2] = [0; 2];
-        reader
-            .read_exact(&mut buf)
-            .expect("unable to read dns header id");
-        self.id = u16::from_be_bytes(buf);
-        self
+    /// Header for a reply to the query carrying this header, the ID, opcode and RD flag are
+    /// copied over as required by https://www.rfc-editor.org/rfc/rfc1035#section-4.1.1
+    pub fn reply(&self, rcode: RCode) -> Header {
+        Header {
+            id: self.id,
+            qr: QueryResponse::Reply,
+            opcode: self.opcode.clone(),
+            rd: self.rd,
+            rcode,
+            ..Default::default()
+        }
     }
-    fn read_qd_count(mut self, reader: &mut DnsReader) -> Self {
-        self.qdcount = Self::read_two_byte_number(reader);
-        self
+
+    fn read_id(mut self, reader: &mut DnsReader) -> ParseResult<Self> {
+        self.id = reader.read_u16()?;
+        Ok(self)
     }
-    fn read_an_count(mut self, reader: &mut DnsReader) -> Self {
-        self.ancount = Self::read_two_byte_number(reader);
-        self
+    fn read_qd_count(mut self, reader: &mut DnsReader) -> ParseResult<Self> {
+        self.qdcount = reader.read_u16()?;
+        Ok(self)
     }
-    fn read_ns_count(mut self, reader: &mut DnsReader) -> Self {
-        self.nscount = Self::read_two_byte_number(reader);
-        self
+    fn read_an_count(mut self, reader: &mut DnsReader) -> ParseResult<Self> {
+        self.ancount = reader.read_u16()?;
+        Ok(self)
     }
-    fn read_ar_count(mut self, reader: &mut DnsReader) -> Self {
-        self.arcount = Self::read_two_byte_number(reader);
-        self
+    fn read_ns_count(mut self, reader: &mut DnsReader) -> ParseResult<Self> {
+        self.nscount = reader.read_u16()?;
+        Ok(self)
     }
-    fn read_two_byte_number(reader: &mut DnsReader) -> u16 {
-        let mut buf: [u8; 2] = [0; 2];
-        reader
-            .read_exact(&mut buf)
-            .expect("unable to read two bytes of number");
-        u16::from_be_bytes(buf)
+    fn read_ar_count(mut self, reader: &mut DnsReader) -> ParseResult<Self> {
+        self.arcount = reader.read_u16()?;
+        Ok(self)
     }
-    fn read_flags(mut self, reader: &mut DnsReader) -> Self {
-        let mut buf: [u8; 2] = [0; 2];
-        reader
-            .read_exact(&mut buf)
-            .expect("unable to read header flags");
-        let mut flags = u16::from_be_bytes(buf);
-
-        self.qr = QueryResponse::from_u8(bits16!(@msb; flags, 1) as u8);
-        flags = flags << 1;
+    fn read_flags(mut self, reader: &mut DnsReader) -> ParseResult<Self> {
+        let offset = reader.cur_pos;
+        let mut flags = reader.read_u16()?;
+
+        let qr = bits16!(@msb; flags, 1) as u8;
+        self.qr = QueryResponse::from_u8(qr).ok_or(ParseError::BadValue {
+            offset,
+            field: "qr",
+            value: qr as u16,
+        })?;
+        flags <<= 1;
 
         self.opcode = OpCode::from_u8(bits16!(@msb; flags, 4) as u8);
-        flags = flags << 4;
+        flags <<= 4;
 
         self.aa = bits16!(@msb; flags, 1) as u8;
-        flags = flags << 1;
+        flags <<= 1;
 
         self.tc = bits16!(@msb; flags, 1) as u8;
-        flags = flags << 1;
+        flags <<= 1;
 
         self.rd = bits16!(@msb; flags, 1) as u8;
-        flags = flags << 1;
+        flags <<= 1;
 
         self.ra = bits16!(@msb; flags, 1) as u8;
-        flags = flags << 1;
+        flags <<= 1;
 
         self.z = bits16!(@msb; flags, 3) as u8;
-        flags = flags << 3;
+        flags <<= 3;
 
-        self.rcode = bits16!(@msb; flags, 4) as u8;
-        flags = flags << 4;
+        self.rcode = RCode::from_u16(bits16!(@msb; flags, 4));
 
-        self
+        Ok(self)
     }
 
     /// Create a bits representation for flags that we can send as payload
@@ -197,51 +259,52 @@ impl Header {
         let mut buf: u16 = 0;
 
         let mut qr = self.qr.as_u8();
-        qr = qr << 7; // qr is only 1 bit, so hack is to discard first 7 bits
+        qr <<= 7; // qr is only 1 bit, so hack is to discard first 7 bits
         let bit = bits!(@msb; qr, 1);
         push_bits(&mut buf, bit);
 
         let mut opcode = self.opcode.as_u8();
-        opcode = opcode << 4;
+        opcode <<= 4;
         for _ in 0..4 {
             let bit = bits!(@msb; opcode, 1);
-            opcode = opcode << 1;
+            opcode <<= 1;
             push_bits(&mut buf, bit);
         }
 
         let mut aa = self.aa;
-        aa = aa << 7;
+        aa <<= 7;
         let bit = bits!(@msb; aa, 1);
         push_bits(&mut buf, bit);
 
         let mut tc = self.tc;
-        tc = tc << 7;
+        tc <<= 7;
         let bit = bits!(@msb; tc, 1);
         push_bits(&mut buf, bit);
 
         let mut rd = self.rd;
-        rd = rd << 7;
+        rd <<= 7;
         let bit = bits!(@msb; rd, 1);
         push_bits(&mut buf, bit);
 
         let mut ra = self.ra;
-        ra = ra << 7;
+        ra <<= 7;
         let bit = bits!(@msb; ra, 1);
         push_bits(&mut buf, bit);
 
         let mut z = self.z;
-        z = z << 5;
+        z <<= 5;
         for _ in 0..3 {
             let bit = bits!(@msb; z, 1);
-            z = z << 1;
+            z <<= 1;
             push_bits(&mut buf, bit);
         }
 
-        let mut rcode = self.rcode;
-        rcode = rcode << 4;
+        // only the lower 4 bits go into the header, the rest is in the OPT record
+        let mut rcode = (self.rcode.as_u16() & 0xF) as u8;
+        rcode <<= 4;
         for _ in 0..4 {
             let bit = bits!(@msb; rcode, 1);
-            rcode = rcode << 1;
+            rcode <<= 1;
             push_bits(&mut buf, bit);
         }
         buf.to_be_bytes()
@@ -252,9 +315,9 @@ impl Header {
 mod tests {
     use std::io::Cursor;
 
-    use crate::common::{dns_reader::DnsReader, AsBytes, Parse};
+    use crate::common::{dns_reader::DnsReader, AsBytes, Parse, ParseError};
 
-    use super::{Header, OpCode, QueryResponse};
+    use super::{Header, OpCode, QueryResponse, RCode};
 
     #[test]
     fn test_parse() {
@@ -267,7 +330,7 @@ mod tests {
             rd: 1,
             ra: 1,
             z: 1,
-            rcode: 1,
+            rcode: RCode::FormErr,
             qdcount: 1,
             ancount: 1,
             nscount: 1,
@@ -276,7 +339,7 @@ mod tests {
         let byte = header.as_bytes();
         let mut reader = DnsReader::new(&byte[..]);
 
-        let parsed = Header::parse(&mut reader);
+        let parsed = Header::parse(&mut reader).unwrap();
         assert_eq!(header.id, parsed.id);
         assert_eq!(header.qr, parsed.qr);
         assert_eq!(header.opcode, parsed.opcode);
@@ -288,6 +351,61 @@ mod tests {
         assert_eq!(header.rcode, parsed.rcode);
     }
 
+    #[test]
+    fn test_parse_truncated() {
+        let bytes = vec![0, 1, 0x81, 0x80, 0, 1, 0];
+        let mut reader = DnsReader::new(&bytes);
+        assert_eq!(
+            Header::parse(&mut reader).unwrap_err(),
+            ParseError::Truncated {
+                offset: 6,
+                n: 2,
+                buf_length: 7
+            }
+        );
+    }
+
+    #[test]
+    fn test_rcode_round_trip() {
+        for value in 0..16 {
+            let header = Header {
+                rcode: RCode::from_u16(value),
+                ..Default::default()
+            };
+            let bytes = header.as_bytes();
+            assert_eq!(bytes[3] as u16, value);
+            let parsed = Header::parse(&mut DnsReader::new(&bytes)).unwrap();
+            assert_eq!(parsed.rcode.as_u16(), value);
+        }
+        assert_eq!(RCode::from_u16(3), RCode::NXDomain);
+        assert_eq!(RCode::from_u16(15), RCode::Reserved(15));
+
+        // extended rcodes are cut down to their lower 4 bits
+        let header = Header {
+            rcode: RCode::BadVers,
+            ..Default::default()
+        };
+        assert_eq!(header.as_bytes()[3], 0);
+    }
+
+    #[test]
+    fn test_reply() {
+        let query = Header {
+            id: 4242,
+            opcode: OpCode::Status,
+            rd: 1,
+            qdcount: 1,
+            ..Default::default()
+        };
+        let reply = query.reply(RCode::NotImp);
+        assert_eq!(reply.id, 4242);
+        assert_eq!(reply.qr, QueryResponse::Reply);
+        assert_eq!(reply.opcode, OpCode::Status);
+        assert_eq!(reply.rd, 1);
+        assert_eq!(reply.rcode, RCode::NotImp);
+        assert_eq!(reply.qdcount, 0);
+    }
+
     #[test]
     fn test_as_bytes() {
         let dns_header = Header {
@@ -299,7 +417,7 @@ mod tests {
             rd: 0,
             ra: 0,
             z: 0,
-            rcode: 0,
+            rcode: RCode::NoError,
             qdcount: 0,
             ancount: 0,
             nscount: 0,
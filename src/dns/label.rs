@@ -1,11 +1,17 @@
-use anyhow::Context;
+use std::fmt::{self, Display};
 
 use crate::{
     bits,
-    common::{dns_reader::DnsReader, AsBytes, Parse},
+    common::{dns_reader::DnsReader, AsBytes, Parse, ParseError, ParseResult},
 };
 
-#[derive(Debug, Clone)]
+/// Names are at most 255 bytes on the wire, length octets and the label end included
+/// https://www.rfc-editor.org/rfc/rfc1035#section-2.3.4
+const MAX_NAME_LENGTH: usize = 255;
+/// A name has at most 127 labels, but real packets never need more than a handful of jumps
+const MAX_POINTER_HOPS: usize = 32;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Label(pub String);
 
 impl AsBytes for Label {
@@ -13,11 +19,13 @@ impl AsBytes for Label {
         let mut labels = self
             .0
             .split(".")
+            // root name is an empty string, it only consists of the label end
+            .filter(|label| !label.is_empty())
             .map(|label| {
                 let len = label.len();
                 let mut vec = Vec::with_capacity(len + 1);
                 vec.push(len as u8);
-                label.chars().map(|c| c as u8).for_each(|b| vec.push(b));
+                vec.extend_from_slice(label.as_bytes());
                 vec
             })
             .flat_map(|f| f.into_iter())
@@ -27,47 +35,72 @@ impl AsBytes for Label {
         labels
     }
 }
+/// Fully qualified presentation form, e.g. `example.com.` or `.` for the root
+impl Display for Label {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.", self.0.trim_end_matches('.'))
+    }
+}
+
 impl Parse for Label {
-    fn parse(reader: &mut DnsReader) -> Self {
+    fn parse(reader: &mut DnsReader) -> ParseResult<Self> {
         let mut label_parts = vec![];
+        // wire length of the name, starts at 1 for the label end
+        let mut name_length = 1;
+        let mut hops = 0;
+        // pointers must point before the label sequence they are part of, so every jump goes
+        // further back in the packet and pointers can't form a loop
+        let mut segment_start = reader.cur_pos;
+        // where the caller continues reading, right after the first pointer if there is one
+        let mut end_pos = None;
+        let mut name_reader = DnsReader {
+            buf: reader.buf,
+            cur_pos: reader.cur_pos,
+        };
         loop {
-            let mut length: [u8; 1] = [0; 1];
-            reader
-                .read_exact(&mut length)
-                .expect("unable to read length for a label");
-            let length = length[0] as usize;
-            if length == 0x00 {
-                break;
-            }
+            let offset = name_reader.cur_pos;
+            let length = name_reader.read_u8()?;
 
-            match length {
-                0x00 => break,
-                // if msb is 11, then it s the pointer, 01 and 10 are reserved but we don't care
-                n if bits!(@msb; length as u8, 2) & 0b11 > 1 => {
-                    let mut one_more: [u8; 1] = [0; 1];
-                    reader
-                        .read_exact(&mut one_more)
-                        .expect("should be able to read one more byte to get the pointer location");
-                    let offset = (bits!(@lsb; length as u8, 6) + one_more[0]) as usize;
-                    let mut pointer_reader = DnsReader {
-                        buf: reader.buf,
-                        cur_pos: offset,
-                    };
-                    let label = Label::parse(&mut pointer_reader);
-                    label_parts.push(label.0);
-                    return Label(label_parts.join("."));
+            match bits!(@msb; length, 2) {
+                0b00 if length == 0x00 => break,
+                0b00 => {}
+                // if msb is 11, then it s the pointer to the rest of the name
+                0b11 => {
+                    let one_more = name_reader.read_u8()?;
+                    let target = ((bits!(@lsb; length, 6) as usize) << 8) | one_more as usize;
+                    if target >= segment_start {
+                        return Err(ParseError::BadPointer { offset, target });
+                    }
+                    hops += 1;
+                    if hops > MAX_POINTER_HOPS {
+                        return Err(ParseError::PointerLoop { offset });
+                    }
+                    end_pos.get_or_insert(name_reader.cur_pos);
+                    segment_start = target;
+                    name_reader.cur_pos = target;
+                    continue;
                 }
-                _ => {} // Not a special case, parse as normal
+                // 01 and 10 are reserved, or in other words the label is longer than 63 bytes
+                _ => {
+                    return Err(ParseError::BadLabel {
+                        offset,
+                        reason: format!("unsupported label type {:#04x}", length),
+                    })
+                }
+            }
+            name_length += 1 + length as usize;
+            if name_length > MAX_NAME_LENGTH {
+                return Err(ParseError::NameTooLong { offset });
             }
-            let mut content = vec![0u8; length];
-            reader
-                .read_exact(&mut content)
-                .with_context(|| format!("unable to read content with size {}", length))
-                .unwrap();
-            let content = String::from_utf8(content).expect("unable to prase content to string");
+            let content = name_reader.read_vec(length as usize)?;
+            let content = String::from_utf8(content).map_err(|_| ParseError::BadLabel {
+                offset,
+                reason: "label is not valid utf-8".to_string(),
+            })?;
             label_parts.push(content);
         }
-        Self(label_parts.join("."))
+        reader.cur_pos = end_pos.unwrap_or(name_reader.cur_pos);
+        Ok(Self(label_parts.join(".")))
     }
 }
 
@@ -75,7 +108,7 @@ impl Parse for Label {
 mod tests {
     use std::io::Cursor;
 
-    use crate::common::{dns_reader::DnsReader, AsBytes, Parse};
+    use crate::common::{dns_reader::DnsReader, AsBytes, Parse, ParseError};
 
     use super::Label;
 
@@ -87,11 +120,202 @@ mod tests {
             vec![7, 101, 120, 97, 109, 112, 108, 101, 3, 99, 111, 109, 0]
         )
     }
+    #[test]
+    fn test_root_as_bytes() {
+        assert_eq!(Label("".to_string()).as_bytes(), vec![0]);
+        assert_eq!(Label("".to_string()).to_string(), ".");
+    }
+
     #[test]
     fn test_parse() {
         let label = Label("example.com".to_string()).as_bytes();
         let mut reader = DnsReader::new(&label);
-        assert_eq!("example.com", Label::parse(&mut reader).0)
+        assert_eq!("example.com", Label::parse(&mut reader).unwrap().0)
+    }
+
+    #[test]
+    fn test_non_ascii_round_trip() {
+        let label = Label("bücher.example".to_string()).as_bytes();
+        assert_eq!(label[0], 7);
+        let mut reader = DnsReader::new(&label);
+        assert_eq!("bücher.example", Label::parse(&mut reader).unwrap().0)
+    }
+
+    #[test]
+    fn test_parse_truncated() {
+        let bytes = vec![7, 101, 120, 97];
+        let mut reader = DnsReader::new(&bytes);
+        assert_eq!(
+            Label::parse(&mut reader).unwrap_err(),
+            ParseError::Truncated {
+                offset: 1,
+                n: 7,
+                buf_length: 4
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_not_utf8() {
+        let bytes = vec![2, 0xff, 0xfe, 0];
+        let mut reader = DnsReader::new(&bytes);
+        assert!(matches!(
+            Label::parse(&mut reader),
+            Err(ParseError::BadLabel { offset: 0, .. })
+        ));
+    }
+
+    fn parse_at(bytes: &[u8], pos: usize) -> Result<Label, ParseError> {
+        let mut reader = DnsReader::new(bytes);
+        reader.cur_pos = pos;
+        Label::parse(&mut reader)
+    }
+
+    #[test]
+    fn test_pointer_past_255() {
+        let mut bytes = vec![0; 300];
+        bytes.extend(Label("example.com".to_string()).as_bytes());
+        let pointer_pos = bytes.len();
+        bytes.extend([3, 119, 119, 119, 0xC1, 0x2C]); // www + pointer to 300
+        bytes.push(0xff);
+        let mut reader = DnsReader::new(&bytes);
+        reader.cur_pos = pointer_pos;
+        assert_eq!(Label::parse(&mut reader).unwrap().0, "www.example.com");
+        // the reader continues right after the pointer
+        assert_eq!(reader.cur_pos, bytes.len() - 1);
+    }
+
+    #[test]
+    fn test_pointer_to_itself() {
+        let bytes = vec![0; 12]
+            .into_iter()
+            .chain([0xC0, 12])
+            .collect::<Vec<_>>();
+        assert_eq!(
+            parse_at(&bytes, 12),
+            Err(ParseError::BadPointer {
+                offset: 12,
+                target: 12
+            })
+        );
+    }
+
+    #[test]
+    fn test_forward_pointer() {
+        let mut bytes = vec![0xC0, 2];
+        bytes.extend(Label("example.com".to_string()).as_bytes());
+        assert_eq!(
+            parse_at(&bytes, 0),
+            Err(ParseError::BadPointer {
+                offset: 0,
+                target: 2
+            })
+        );
+    }
+
+    #[test]
+    fn test_pointer_loop_between_names() {
+        // a.<pointer to b> at 0, b.<pointer to a> at 4
+        let bytes = vec![1, 97, 0xC0, 4, 1, 98, 0xC0, 0];
+        assert_eq!(
+            parse_at(&bytes, 4),
+            Err(ParseError::BadPointer {
+                offset: 2,
+                target: 4
+            })
+        );
+    }
+
+    #[test]
+    fn test_pointer_inside_pointed_to_segment() {
+        // 1 'a' then a pointer back to its own segment start
+        let bytes = vec![0, 0, 1, 97, 0xC0, 2];
+        assert_eq!(
+            parse_at(&bytes, 2),
+            Err(ParseError::BadPointer {
+                offset: 4,
+                target: 2
+            })
+        );
+    }
+
+    #[test]
+    fn test_pointer_hop_limit() {
+        // every pointer points to the one before it, the first one to the root
+        let mut bytes = vec![0];
+        for i in 0..40u16 {
+            let target = if i == 0 { 0 } else { 1 + (i - 1) * 2 };
+            bytes.extend((0xC000 | target).to_be_bytes());
+        }
+        assert_eq!(parse_at(&bytes, 1 + 5 * 2).unwrap().0, "");
+        assert_eq!(
+            parse_at(&bytes, 1 + 39 * 2),
+            Err(ParseError::PointerLoop { offset: 1 + 7 * 2 })
+        );
+    }
+
+    #[test]
+    fn test_truncated_pointer() {
+        assert!(matches!(
+            parse_at(&[3, 97, 98, 99, 0xC0], 0),
+            Err(ParseError::Truncated { offset: 5, .. })
+        ));
+    }
+
+    #[test]
+    fn test_reserved_label_type() {
+        for length in [0x40, 0x7f, 0x80, 0xbf] {
+            let mut bytes = vec![length];
+            bytes.extend(vec![97; 0xbf]);
+            bytes.push(0);
+            assert!(matches!(
+                parse_at(&bytes, 0),
+                Err(ParseError::BadLabel { offset: 0, .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn test_name_length_limit() {
+        // 4 labels of 63 bytes are 256 bytes on the wire with the label end
+        let label = |n| vec![97u8; n];
+        let mut bytes = vec![];
+        for _ in 0..3 {
+            bytes.push(63);
+            bytes.extend(label(63));
+        }
+        let mut max = bytes.clone();
+        max.push(61);
+        max.extend(label(61));
+        max.push(0);
+        assert_eq!(max.len(), 255);
+        assert_eq!(parse_at(&max, 0).unwrap().0.len(), 253);
+
+        bytes.push(62);
+        bytes.extend(label(62));
+        bytes.push(0);
+        assert_eq!(
+            parse_at(&bytes, 0),
+            Err(ParseError::NameTooLong { offset: 192 })
+        );
+    }
+
+    #[test]
+    fn test_name_length_limit_across_pointers() {
+        let mut bytes = vec![];
+        for _ in 0..3 {
+            bytes.push(63);
+            bytes.extend(vec![97u8; 63]);
+        }
+        bytes.push(0);
+        let pointer_pos = bytes.len();
+        bytes.push(62);
+        bytes.extend(vec![98u8; 62]);
+        bytes.extend([0xC0, 0]);
+        assert_eq!(
+            parse_at(&bytes, pointer_pos),
+            Err(ParseError::NameTooLong { offset: 128 })
+        );
     }
 
     #[test]
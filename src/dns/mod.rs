@@ -1,22 +1,29 @@
 #![allow(unused)]
 
+use std::fmt::{self, Display};
 use std::io::Read;
 
 use anyhow::Context;
 
 use crate::{
     bits,
-    common::{dns_reader::DnsReader, AsBytes, Parse},
+    common::{dns_reader::DnsReader, AsBytes, Parse, ParseResult},
 };
 pub mod answer;
+pub mod cache;
+pub mod edns;
 pub mod header;
 pub mod label;
 pub mod packet;
 pub mod question;
 pub mod resolver;
 pub mod server;
+pub mod tcp;
+pub mod zone;
 
 /// https://www.rfc-editor.org/rfc/rfc1035#section-3.2.2
+/// QTYPE only values are from https://www.rfc-editor.org/rfc/rfc1035#section-3.2.3
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub enum RecordType {
     A,
@@ -35,11 +42,50 @@ pub enum RecordType {
     MINFO,
     MX,
     TXT,
+    /// EDNS pseudo-record https://www.rfc-editor.org/rfc/rfc6891#section-6.1.1
+    OPT,
+    /// QTYPE - request for a transfer of an entire zone
+    AXFR,
+    /// QTYPE - request for mailbox-related records (MB, MG or MR)
+    MAILB,
+    /// QTYPE - request for mail agent RRs (Obsolete - see MX)
+    MAILA,
+    /// QTYPE `*` - request for all records
+    ANY,
+    /// Any type we don't know about, kept as is so it can be written back unchanged
+    Unknown(u16),
 }
-impl AsBytes for RecordType {
-    fn as_bytes(&self) -> Vec<u8> {
+impl RecordType {
+    pub fn from_u16(value: u16) -> Self {
+        use RecordType::*;
+        match value {
+            1 => A,
+            2 => NS,
+            3 => MD,
+            4 => MF,
+            5 => CNAME,
+            6 => SOA,
+            7 => MB,
+            8 => MG,
+            9 => MR,
+            10 => NULL,
+            11 => WKS,
+            12 => PTR,
+            13 => HINFO,
+            14 => MINFO,
+            15 => MX,
+            16 => TXT,
+            41 => OPT,
+            252 => AXFR,
+            253 => MAILB,
+            254 => MAILA,
+            255 => ANY,
+            _ => Unknown(value),
+        }
+    }
+    pub fn as_u16(&self) -> u16 {
         use RecordType::*;
-        let value: u16 = match self {
+        match self {
             A => 1,
             NS => 2,
             MD => 3,
@@ -56,55 +102,127 @@ impl AsBytes for RecordType {
             MINFO => 14,
             MX => 15,
             TXT => 16,
-        };
-        return value.to_be_bytes().to_vec();
+            OPT => 41,
+            AXFR => 252,
+            MAILB => 253,
+            MAILA => 254,
+            ANY => 255,
+            Unknown(value) => *value,
+        }
     }
 }
-impl Parse for RecordType {
-    fn parse(reader: &mut DnsReader) -> Self {
-        use RecordType::*;
-        let mut buf: [u8; 2] = [0; 2];
-        reader
-            .read_exact(&mut buf)
-            .expect("unable to parse record type");
-        match u16::from_be_bytes(buf) {
-            1 => A,
-            n => unimplemented!("{n} record type are not implemented yet!!!"),
+/// Mnemonic, or `TYPE<n>` for types we don't know about
+/// https://www.rfc-editor.org/rfc/rfc3597#section-5
+impl Display for RecordType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RecordType::ANY => write!(f, "*"),
+            RecordType::Unknown(value) => write!(f, "TYPE{value}"),
+            known => write!(f, "{known:?}"),
         }
     }
 }
+impl AsBytes for RecordType {
+    fn as_bytes(&self) -> Vec<u8> {
+        self.as_u16().to_be_bytes().to_vec()
+    }
+}
+impl Parse for RecordType {
+    fn parse(reader: &mut DnsReader) -> ParseResult<Self> {
+        Ok(RecordType::from_u16(reader.read_u16()?))
+    }
+}
 
 /// https://www.rfc-editor.org/rfc/rfc1035#section-3.2.4
+/// QCLASS only values are from https://www.rfc-editor.org/rfc/rfc1035#section-3.2.5
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub enum RecordClass {
     IN,
     CS,
     CH,
     HS,
+    /// QCLASS `*` - any class
+    ANY,
+    /// Any class we don't know about, kept as is so it can be written back unchanged
+    Unknown(u16),
 }
-impl AsBytes for RecordClass {
-    fn as_bytes(&self) -> Vec<u8> {
+impl RecordClass {
+    pub fn from_u16(value: u16) -> Self {
         use RecordClass::*;
-        let value: u16 = match self {
+        match value {
+            1 => IN,
+            2 => CS,
+            3 => CH,
+            4 => HS,
+            255 => ANY,
+            _ => Unknown(value),
+        }
+    }
+    pub fn as_u16(&self) -> u16 {
+        use RecordClass::*;
+        match self {
             IN => 1,
             CS => 2,
             CH => 3,
             HS => 4,
-        };
-        return value.to_be_bytes().to_vec();
+            ANY => 255,
+            Unknown(value) => *value,
+        }
+    }
+}
+/// Mnemonic, or `CLASS<n>` for classes we don't know about
+/// https://www.rfc-editor.org/rfc/rfc3597#section-5
+impl Display for RecordClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RecordClass::ANY => write!(f, "*"),
+            RecordClass::Unknown(value) => write!(f, "CLASS{value}"),
+            known => write!(f, "{known:?}"),
+        }
+    }
+}
+impl AsBytes for RecordClass {
+    fn as_bytes(&self) -> Vec<u8> {
+        self.as_u16().to_be_bytes().to_vec()
     }
 }
 
 impl Parse for RecordClass {
-    fn parse(reader: &mut DnsReader) -> Self {
-        use RecordClass::*;
-        let mut buf: [u8; 2] = [0; 2];
-        reader
-            .read_exact(&mut buf)
-            .expect("unable to parse record class");
-        match u16::from_be_bytes(buf) {
-            1 => IN,
-            n => unimplemented!("{n} record class are not implemented yet!!!"),
+    fn parse(reader: &mut DnsReader) -> ParseResult<Self> {
+        Ok(RecordClass::from_u16(reader.read_u16()?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::common::{dns_reader::DnsReader, AsBytes, Parse};
+
+    use super::{RecordClass, RecordType};
+
+    #[test]
+    fn test_record_type_round_trip() {
+        for value in (0..=300u16).chain([28, 65, 0xffff]) {
+            let bytes = value.to_be_bytes();
+            let mut reader = DnsReader::new(&bytes);
+            let typez = RecordType::parse(&mut reader).unwrap();
+            assert_eq!(typez.as_bytes(), bytes.to_vec());
+        }
+        assert_eq!(RecordType::from_u16(15), RecordType::MX);
+        assert_eq!(RecordType::from_u16(255), RecordType::ANY);
+        assert_eq!(RecordType::from_u16(28), RecordType::Unknown(28));
+    }
+
+    #[test]
+    fn test_record_class_round_trip() {
+        for value in [0u16, 1, 2, 3, 4, 5, 254, 255, 4096, 0xffff] {
+            let bytes = value.to_be_bytes();
+            let mut reader = DnsReader::new(&bytes);
+            let class = RecordClass::parse(&mut reader).unwrap();
+            assert_eq!(class.as_bytes(), bytes.to_vec());
         }
+        assert_eq!(RecordClass::from_u16(3), RecordClass::CH);
+        assert_eq!(RecordClass::from_u16(255), RecordClass::ANY);
+        assert_eq!(RecordClass::from_u16(42), RecordClass::Unknown(42));
     }
 }
@@ -1,18 +1,28 @@
 use tracing_subscriber::field::display::Messages;
 
-use crate::common::{dns_reader::DnsReader, AsBytes, Parse};
+use crate::common::{
+    dns_reader::DnsReader, dns_writer::DnsWriter, AsBytes, Parse, ParseError, ParseResult, WriteTo,
+};
 
 use super::answer::Answer;
-use super::header::{Header, OpCode, QueryResponse};
+use super::edns::{Edns, MIN_UDP_PAYLOAD_SIZE};
+use super::header::{Header, OpCode, QueryResponse, RCode};
 use super::question::Question;
+use super::RecordType;
 
 pub mod packet_builder;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Packet {
     pub header: Header,
     pub questions: Vec<Question>,
     pub answers: Vec<Answer>,
+    /// Resource records pointing toward an authority
+    pub authorities: Vec<Answer>,
+    /// Resource records holding additional information, e.g. glue
+    pub additionals: Vec<Answer>,
+    /// Taken out of the OPT pseudo-record in the additional section
+    pub edns: Option<Edns>,
 }
 
 pub trait Merge<T> {
@@ -32,7 +42,12 @@ impl Merge<Packet> for Vec<Packet> {
                 rd: 1,
                 ra: 0,
                 z: 0,
-                rcode: 0,
+                // a single failed lookup decides the response code for the merged packet
+                rcode: self
+                    .iter()
+                    .map(|p| p.header.rcode)
+                    .find(|rcode| *rcode != RCode::NoError)
+                    .unwrap_or_default(),
                 qdcount: count as u16,
                 ancount: count as u16,
                 nscount: 0,
@@ -40,11 +55,67 @@ impl Merge<Packet> for Vec<Packet> {
             })
             .questions(self.iter().flat_map(|p| p.questions.clone()).collect())
             .answers(self.iter().flat_map(|p| p.answers.clone()).collect())
+            .authorities(self.iter().flat_map(|p| p.authorities.clone()).collect())
+            .additionals(self.iter().flat_map(|p| p.additionals.clone()).collect())
             .build()
     }
 }
 
 impl Packet {
+    /// Largest UDP response the sender of this packet is able to receive
+    pub fn max_udp_payload_size(&self) -> u16 {
+        self.edns
+            .as_ref()
+            .map(|edns| edns.negotiated_payload_size())
+            .unwrap_or(MIN_UDP_PAYLOAD_SIZE)
+    }
+
+    /// Serialize to at most `max_size` bytes, as long as the questions fit. Records that don't fit
+    /// are dropped, TC is set only when answer or authority records had to go, losing additional
+    /// records is fine https://www.rfc-editor.org/rfc/rfc2181#section-9
+    pub fn as_bytes_truncated(&self, max_size: usize) -> Vec<u8> {
+        let bytes = self.as_bytes();
+        if bytes.len() <= max_size {
+            return bytes;
+        }
+        // OPT record is always kept, so it gets its room first
+        let opt = self
+            .edns
+            .as_ref()
+            .map(|edns| edns.as_answer(self.header.rcode));
+        let limit = max_size.saturating_sub(opt.as_ref().map_or(0, |o| o.as_bytes().len()));
+
+        let mut header = self.header.clone();
+        let mut writer = DnsWriter::new();
+        writer.write(&header.as_bytes());
+        self.questions.iter().for_each(|q| q.write_to(&mut writer));
+        let mut counts = [0u16; 3];
+        let sections = [&self.answers, &self.authorities, &self.additionals];
+        'sections: for (i, section) in sections.into_iter().enumerate() {
+            for record in section {
+                let pos = writer.pos();
+                record.write_to(&mut writer);
+                if writer.pos() > limit {
+                    writer.truncate(pos);
+                    if i < 2 {
+                        header.tc = 1;
+                    }
+                    break 'sections;
+                }
+                counts[i] += 1;
+            }
+        }
+        if let Some(opt) = opt {
+            opt.write_to(&mut writer);
+        }
+        header.ancount = counts[0];
+        header.nscount = counts[1];
+        header.arcount = counts[2] + self.edns.iter().count() as u16;
+        writer.set(0, &header.as_bytes());
+        writer.into_bytes()
+    }
+
+    /// One query per question, each advertising our own EDNS as that is hop by hop
     pub fn split(&self) -> Vec<Self> {
         self.questions
             .iter()
@@ -57,6 +128,10 @@ impl Packet {
                         ..self.header.clone()
                     })
                     .question(question.clone())
+                    .edns(Some(Edns {
+                        dnssec_ok: self.edns.as_ref().is_some_and(|e| e.dnssec_ok),
+                        ..Default::default()
+                    }))
                     .build()
             })
             .collect()
@@ -65,30 +140,65 @@ impl Packet {
 
 impl AsBytes for Packet {
     fn as_bytes(&self) -> Vec<u8> {
-        // in most cases DNS packet shouldn't be more than 512 bytes
-        let mut buf: Vec<u8> = Vec::with_capacity(512);
-        buf.extend(self.header.as_bytes());
-        self.questions.iter().for_each(|q| buf.extend(q.as_bytes()));
-        self.answers.iter().for_each(|a| buf.extend(a.as_bytes()));
-        buf
+        let mut writer = DnsWriter::new();
+        self.write_to(&mut writer);
+        writer.into_bytes()
+    }
+}
+
+impl WriteTo for Packet {
+    fn write_to(&self, writer: &mut DnsWriter) {
+        writer.write(&self.header.as_bytes());
+        self.questions.iter().for_each(|q| q.write_to(writer));
+        self.answers.iter().for_each(|a| a.write_to(writer));
+        self.authorities.iter().for_each(|a| a.write_to(writer));
+        self.additionals.iter().for_each(|a| a.write_to(writer));
+        if let Some(edns) = &self.edns {
+            edns.as_answer(self.header.rcode).write_to(writer);
+        }
     }
 }
 
 impl Parse for Packet {
-    fn parse(dns_reader: &mut DnsReader) -> Self {
-        let header = Header::parse(dns_reader);
-        let questions: Vec<Question> = (0..header.qdcount)
+    fn parse(dns_reader: &mut DnsReader) -> ParseResult<Self> {
+        let mut header = Header::parse(dns_reader)?;
+        let questions = (0..header.qdcount)
             .map(|_| Question::parse(dns_reader))
-            .collect();
-        let answers: Vec<Answer> = (0..header.ancount)
+            .collect::<ParseResult<Vec<_>>>()?;
+        let answers = (0..header.ancount)
+            .map(|_| Answer::parse(dns_reader))
+            .collect::<ParseResult<Vec<_>>>()?;
+        let authorities = (0..header.nscount)
             .map(|_| Answer::parse(dns_reader))
-            .collect();
+            .collect::<ParseResult<Vec<_>>>()?;
+        let mut additionals = vec![];
+        let mut edns = None;
+        for _ in 0..header.arcount {
+            let offset = dns_reader.cur_pos;
+            let additional = Answer::parse(dns_reader)?;
+            if additional.typez != RecordType::OPT {
+                additionals.push(additional);
+                continue;
+            }
+            if edns.is_some() {
+                return Err(ParseError::BadRData {
+                    offset,
+                    reason: "more than one OPT record".to_string(),
+                });
+            }
+            let (opt, extended_rcode) = Edns::from_answer(additional, offset)?;
+            header.rcode = RCode::from_u16(((extended_rcode as u16) << 4) | header.rcode.as_u16());
+            edns = Some(opt);
+        }
 
-        Packet::builder()
+        Ok(Packet::builder()
             .header(header)
             .questions(questions)
             .answers(answers)
-            .build()
+            .authorities(authorities)
+            .additionals(additionals)
+            .edns(edns)
+            .build())
     }
 }
 
@@ -96,22 +206,25 @@ impl Parse for Packet {
 mod tests {
     use pretty_assertions::assert_eq;
     use std::io::{BufRead, BufReader, Cursor, Read};
+    use std::net::Ipv4Addr;
 
     use crate::{
-        common::{dns_reader::DnsReader, AsBytes, Parse},
+        common::{dns_reader::DnsReader, AsBytes, Parse, ParseError},
         config::setup_log,
         dns::{
             answer::{Answer, RData},
-            header::Header,
+            edns::Edns,
+            header::{Header, RCode},
             label::Label,
             question::Question,
             RecordClass, RecordType,
         },
     };
 
-    use super::Packet;
+    use super::{Merge, Packet};
 
     #[test]
+    #[allow(clippy::field_reassign_with_default)]
     fn test_packet_parse() {
         let mut header = Header::default();
         header.id = 99;
@@ -125,10 +238,11 @@ mod tests {
             .build();
         let packet_byte = packet.as_bytes();
         let mut reader = DnsReader::new(&packet_byte);
-        let header = Header::parse(&mut reader);
+        let header = Header::parse(&mut reader).unwrap();
         assert_eq!(header.id, 99);
     }
     #[test]
+    #[allow(clippy::field_reassign_with_default)]
     fn test_as_bytes() {
         let mut header = Header::default();
         header.qdcount = 1;
@@ -144,7 +258,7 @@ mod tests {
                 typez: RecordType::A,
                 class: RecordClass::IN,
                 ttl: 60,
-                rdata: RData("8.8.8.8".to_string()),
+                rdata: RData::A(Ipv4Addr::new(8, 8, 8, 8)),
             })
             .build();
         let bytes = packet.as_bytes();
@@ -152,8 +266,9 @@ mod tests {
             bytes,
             vec![
                 0, 0, 0, 0, 0, 1, 0, 1, 0, 0, 0, 0, 12, 99, 111, 100, 101, 99, 114, 97, 102, 116,
-                101, 114, 115, 2, 105, 111, 0, 0, 1, 0, 1, 12, 99, 111, 100, 101, 99, 114, 97, 102,
-                116, 101, 114, 115, 2, 105, 111, 0, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 8, 8, 8, 8
+                101, 114, 115, 2, 105, 111, 0, 0, 1, 0, 1, //
+                192, 12, // answer name points to the question name
+                0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 8, 8, 8, 8
             ]
         )
     }
@@ -174,13 +289,218 @@ mod tests {
         // 12, 99, 111, 100, 101, 99, 114, 97, 102, 116, 101, 114, 115, 2, 105, 111, 0,
         //  0, 1, 0, 1]
         let mut reader = DnsReader::new(&bytes);
-        let packet = Packet::parse(&mut reader);
+        let packet = Packet::parse(&mut reader).unwrap();
 
         assert_eq!(packet.header.id, 63823);
         assert_eq!(packet.header.qdcount, 1);
         assert_eq!(packet.questions.first().unwrap().name.0, "codecrafters.io");
     }
 
+    #[test]
+    fn test_authority_and_additional_sections() {
+        let ns = |name: &str| Answer {
+            label: Label("example.com".to_string()),
+            typez: RecordType::NS,
+            class: RecordClass::IN,
+            ttl: 3600,
+            rdata: RData::NS(Label(name.to_string())),
+        };
+        let glue = Answer {
+            label: Label("ns1.example.com".to_string()),
+            typez: RecordType::A,
+            class: RecordClass::IN,
+            ttl: 3600,
+            rdata: RData::A(Ipv4Addr::new(192, 0, 2, 1)),
+        };
+        let packet = Packet::builder()
+            .header(Header {
+                id: 42,
+                ..Default::default()
+            })
+            .question(Question {
+                name: Label("www.example.com".to_string()),
+                class: RecordClass::IN,
+                typez: RecordType::A,
+            })
+            .authority(ns("ns1.example.com"))
+            .authority(ns("ns2.example.com"))
+            .additional(glue.clone())
+            .build();
+        assert_eq!(packet.header.nscount, 2);
+        assert_eq!(packet.header.arcount, 1);
+
+        let bytes = packet.as_bytes();
+        let parsed = Packet::parse(&mut DnsReader::new(&bytes)).unwrap();
+        assert_eq!(parsed.header.nscount, 2);
+        assert_eq!(parsed.header.arcount, 1);
+        assert_eq!(parsed.answers.len(), 0);
+        assert_eq!(
+            parsed.authorities[1].rdata,
+            RData::NS(Label("ns2.example.com".to_string()))
+        );
+        assert_eq!(parsed.additionals[0].rdata, glue.rdata);
+
+        let merged = vec![parsed, packet].merge();
+        assert_eq!(merged.header.nscount, 4);
+        assert_eq!(merged.header.arcount, 2);
+        assert_eq!(merged.authorities.len(), 4);
+    }
+
+    #[test]
+    fn test_edns_round_trip() {
+        let edns = Edns {
+            udp_payload_size: 4096,
+            dnssec_ok: true,
+            ..Default::default()
+        };
+        let packet = Packet::builder()
+            .header(Header {
+                rcode: RCode::BadVers,
+                ..Default::default()
+            })
+            .question(Question {
+                name: Label("codecrafters.io".to_string()),
+                class: RecordClass::IN,
+                typez: RecordType::A,
+            })
+            .edns(Some(edns.clone()))
+            .build();
+        assert_eq!(packet.header.arcount, 1);
+
+        let bytes = packet.as_bytes();
+        let parsed = Packet::parse(&mut DnsReader::new(&bytes)).unwrap();
+        assert_eq!(parsed.edns, Some(edns));
+        assert_eq!(parsed.header.rcode, RCode::BadVers);
+        assert!(parsed.additionals.is_empty());
+        assert_eq!(parsed.max_udp_payload_size(), 1232);
+        assert_eq!(parsed.as_bytes(), bytes);
+    }
+
+    fn a_record(name: &str, i: u8) -> Answer {
+        Answer {
+            label: Label(name.to_string()),
+            typez: RecordType::A,
+            class: RecordClass::IN,
+            ttl: 60,
+            rdata: RData::A(Ipv4Addr::new(10, 0, 0, i)),
+        }
+    }
+
+    #[test]
+    fn test_as_bytes_truncated() {
+        let packet = Packet::builder()
+            .question(Question {
+                name: Label("codecrafters.io".to_string()),
+                class: RecordClass::IN,
+                typez: RecordType::A,
+            })
+            .answers((0..10).map(|i| a_record("codecrafters.io", i)).collect())
+            .build();
+        // header 12 + question 21 + 16 per compressed answer
+        assert_eq!(packet.as_bytes().len(), 193);
+        assert_eq!(packet.as_bytes_truncated(193), packet.as_bytes());
+
+        let bytes = packet.as_bytes_truncated(100);
+        assert_eq!(bytes.len(), 33 + 4 * 16);
+        let parsed = Packet::parse(&mut DnsReader::new(&bytes)).unwrap();
+        assert_eq!(parsed.header.tc, 1);
+        assert_eq!(parsed.header.ancount, 4);
+        assert_eq!(
+            parsed.answers[3].rdata,
+            RData::A(Ipv4Addr::new(10, 0, 0, 3))
+        );
+    }
+
+    #[test]
+    fn test_as_bytes_truncated_keeps_opt() {
+        let packet = Packet::builder()
+            .question(Question {
+                name: Label("codecrafters.io".to_string()),
+                class: RecordClass::IN,
+                typez: RecordType::A,
+            })
+            .answers((0..10).map(|i| a_record("codecrafters.io", i)).collect())
+            .edns(Some(Edns::default()))
+            .build();
+        let bytes = packet.as_bytes_truncated(100);
+        let parsed = Packet::parse(&mut DnsReader::new(&bytes)).unwrap();
+        assert!(bytes.len() <= 100);
+        assert_eq!(parsed.header.tc, 1);
+        assert_eq!(parsed.answers.len(), 3);
+        assert_eq!(parsed.edns, Some(Edns::default()));
+    }
+
+    #[test]
+    fn test_dropping_additionals_is_not_truncation() {
+        let packet = Packet::builder()
+            .question(Question {
+                name: Label("codecrafters.io".to_string()),
+                class: RecordClass::IN,
+                typez: RecordType::A,
+            })
+            .answer(a_record("codecrafters.io", 1))
+            .additionals(
+                (0..10)
+                    .map(|i| a_record(&format!("ns{i}.codecrafters.io"), i))
+                    .collect(),
+            )
+            .build();
+        let bytes = packet.as_bytes_truncated(100);
+        let parsed = Packet::parse(&mut DnsReader::new(&bytes)).unwrap();
+        assert_eq!(parsed.header.tc, 0);
+        assert_eq!(parsed.answers.len(), 1);
+        assert!(parsed.additionals.len() < 10);
+    }
+
+    #[test]
+    fn test_max_udp_payload_size_without_edns() {
+        let packet = Packet::builder().build();
+        assert_eq!(packet.max_udp_payload_size(), 512);
+    }
+
+    #[test]
+    fn test_more_than_one_opt() {
+        let opt = Edns::default().as_answer(RCode::NoError);
+        let packet = Packet::builder()
+            .additional(opt.clone())
+            .additional(opt)
+            .build();
+        let bytes = packet.as_bytes();
+        assert_eq!(
+            Packet::parse(&mut DnsReader::new(&bytes)).unwrap_err(),
+            ParseError::BadRData {
+                offset: 23,
+                reason: "more than one OPT record".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn test_opt_owner_not_root() {
+        let mut opt = Edns::default().as_answer(RCode::NoError);
+        opt.label = Label("example.com".to_string());
+        let bytes = Packet::builder().additional(opt).build().as_bytes();
+        assert!(matches!(
+            Packet::parse(&mut DnsReader::new(&bytes)),
+            Err(ParseError::BadRData { offset: 12, .. })
+        ));
+    }
+
+    #[test]
+    fn test_parse_truncated_packet() {
+        let bytes = vec![
+            249, 79, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0, 12, 99, 111, 100, 101, 99, 114, 97, 102, 116,
+            101, 114, 115, 2, 105, 111, 0, 0, 1, 0, 1,
+        ];
+        for len in 0..bytes.len() {
+            let mut reader = DnsReader::new(&bytes[..len]);
+            assert!(matches!(
+                Packet::parse(&mut reader),
+                Err(ParseError::Truncated { .. })
+            ));
+        }
+    }
+
     #[test]
     fn test_parse_compression_packet() {
         let bytes = vec![
@@ -207,7 +527,7 @@ mod tests {
             0, 1, 0, 1,
         ];
         let mut reader = DnsReader::new(&bytes);
-        let packet = Packet::parse(&mut reader);
+        let packet = Packet::parse(&mut reader).unwrap();
         eprintln!("PACKET: {:?}", packet);
         assert_eq!(packet.header.id, 50720);
         assert_eq!(packet.header.qdcount, 2);
@@ -232,7 +552,7 @@ mod tests {
                         typez: RecordType::A,
                         class: RecordClass::IN,
                         ttl: 60,
-                        rdata: RData("8.8.8.8".to_string()),
+                        rdata: RData::A(Ipv4Addr::new(8, 8, 8, 8)),
                     })
                     .collect(),
             )
@@ -240,8 +560,22 @@ mod tests {
             .build();
         assert_eq!(
             res_packet.as_bytes(),
-            vec![198, 32, 1, 0, 0, 2, 0, 2, 0, 0, 0, 0, 3, 97, 98, 99, 17, 108, 111, 110, 103, 97, 115, 115, 100, 111, 109, 97, 105, 110, 110, 97, 109, 101, 3, 99, 111, 109, 0, 0, 1, 0, 1, 3, 100, 101, 102, 17, 108, 111, 110, 103, 97, 115, 115, 100, 111, 109, 97, 105, 110, 110, 97, 109, 101, 3, 99, 111, 109, 0, 0, 1, 0, 1, 3, 97, 98, 99, 17, 108, 111, 110, 103, 97, 115, 115, 100, 111, 109, 97, 105, 110, 110, 97, 109, 101, 3, 99, 111, 109, 0, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 8, 8, 8, 8, 3, 100, 101, 102, 17, 108, 111, 110, 103, 97, 115, 115, 100, 111, 109, 97, 105, 110, 110, 97, 109, 101, 3, 99, 111, 109, 0, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 8, 8, 8, 8]
+            vec![
+                198, 32, 1, 0, 0, 2, 0, 2, 0, 0, 0, 0, //
+                3, 97, 98, 99, 17, 108, 111, 110, 103, 97, 115, 115, 100, 111, 109, 97, 105, 110,
+                110, 97, 109, 101, 3, 99, 111, 109, 0, 0, 1, 0, 1, //
+                3, 100, 101, 102, 192, 16, 0, 1, 0,
+                1, // def + pointer to longassdomainname.com
+                192, 12, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 8, 8, 8, 8, // pointer to abc...
+                192, 43, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 8, 8, 8, 8, // pointer to def...
+            ]
         );
-        assert_eq!(res_packet.as_bytes().len(), 156);
+        assert_eq!(res_packet.as_bytes().len(), 85);
+
+        // compressed bytes parse back to the same packet
+        let bytes = res_packet.as_bytes();
+        let parsed = Packet::parse(&mut DnsReader::new(&bytes)).unwrap();
+        assert_eq!(parsed.answers[1].label.0, "def.longassdomainname.com");
+        assert_eq!(parsed.as_bytes(), bytes);
     }
 }
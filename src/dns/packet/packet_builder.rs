@@ -1,5 +1,4 @@
-use crate::dns::header::OpCode;
-use crate::dns::{answer::Answer, header::Header, question::Question};
+use crate::dns::{answer::Answer, edns::Edns, header::Header, question::Question};
 
 use super::Packet;
 
@@ -8,6 +7,9 @@ pub struct PacketBuilder {
     header: Header,
     questions: Vec<Question>,
     answers: Vec<Answer>,
+    authorities: Vec<Answer>,
+    additionals: Vec<Answer>,
+    edns: Option<Edns>,
 }
 
 impl PacketBuilder {
@@ -31,22 +33,42 @@ impl PacketBuilder {
         self.answers.push(answer);
         self
     }
+    pub fn authorities(mut self, authorities: Vec<Answer>) -> Self {
+        self.authorities = authorities;
+        self
+    }
+    pub fn authority(mut self, authority: Answer) -> Self {
+        self.authorities.push(authority);
+        self
+    }
+    pub fn additionals(mut self, additionals: Vec<Answer>) -> Self {
+        self.additionals = additionals;
+        self
+    }
+    pub fn additional(mut self, additional: Answer) -> Self {
+        self.additionals.push(additional);
+        self
+    }
+    pub fn edns(mut self, edns: Option<Edns>) -> Self {
+        self.edns = edns;
+        self
+    }
     pub fn build(self) -> Packet {
-        let packet = Packet {
+        Packet {
             header: Header {
                 qdcount: self.questions.len() as u16,
                 ancount: self.answers.len() as u16,
-                rcode: if self.header.opcode == OpCode::Query {
-                    0
-                } else {
-                    4
-                },
+                nscount: self.authorities.len() as u16,
+                // OPT pseudo-record is counted with the additional records
+                arcount: (self.additionals.len() + self.edns.iter().count()) as u16,
                 ..self.header.clone()
             },
             questions: self.questions,
             answers: self.answers,
-        };
-        packet
+            authorities: self.authorities,
+            additionals: self.additionals,
+            edns: self.edns,
+        }
     }
 }
 
@@ -4,7 +4,7 @@ use tracing::debug;
 
 use crate::{
     bits, bits16,
-    common::{dns_reader::DnsReader, AsBytes, Parse},
+    common::{dns_reader::DnsReader, dns_writer::DnsWriter, AsBytes, Parse, ParseResult, WriteTo},
 };
 
 use super::{label::Label, RecordClass, RecordType};
@@ -16,24 +16,45 @@ pub struct Question {
     pub class: RecordClass,
 }
 
+impl Question {
+    /// Same name without regard to case, same type and class. A reply has to carry the
+    /// question of the query it answers
+    /// https://www.rfc-editor.org/rfc/rfc5452#section-9.1
+    pub fn matches(&self, other: &Question) -> bool {
+        self.name
+            .0
+            .trim_end_matches('.')
+            .eq_ignore_ascii_case(other.name.0.trim_end_matches('.'))
+            && self.typez == other.typez
+            && self.class == other.class
+    }
+}
+
 impl AsBytes for Question {
     fn as_bytes(&self) -> Vec<u8> {
         let mut buf = self.name.as_bytes();
         buf.extend(self.typez.as_bytes());
         buf.extend(self.class.as_bytes());
-        return buf;
+        buf
+    }
+}
+impl WriteTo for Question {
+    fn write_to(&self, writer: &mut DnsWriter) {
+        writer.write_label(&self.name);
+        writer.write(&self.typez.as_bytes());
+        writer.write(&self.class.as_bytes());
     }
 }
 impl Parse for Question {
-    fn parse(reader: &mut DnsReader) -> Self {
-        let label = Label::parse(reader);
-        let record_type = RecordType::parse(reader);
-        let record_class = RecordClass::parse(reader);
-        Self {
+    fn parse(reader: &mut DnsReader) -> ParseResult<Self> {
+        let label = Label::parse(reader)?;
+        let record_type = RecordType::parse(reader)?;
+        let record_class = RecordClass::parse(reader)?;
+        Ok(Self {
             name: label,
             typez: record_type,
             class: record_class,
-        }
+        })
     }
 }
 
@@ -74,9 +95,26 @@ mod tests {
         let mut actual_bytes = message.as_bytes();
         let mut reader = DnsReader::new(&actual_bytes);
 
-        let parsed = Question::parse(&mut reader);
+        let parsed = Question::parse(&mut reader).unwrap();
         assert_eq!(parsed.name.0, message.name.0);
         assert_eq!(parsed.typez, message.typez);
         assert_eq!(parsed.class, message.class);
     }
+
+    #[test]
+    fn test_matches() {
+        let question = |name: &str, typez| Question {
+            name: Label(name.to_string()),
+            typez,
+            class: RecordClass::IN,
+        };
+        let query = question("Google.com", RecordType::A);
+        assert!(query.matches(&question("google.COM.", RecordType::A)));
+        assert!(!query.matches(&question("google.com", RecordType::MX)));
+        assert!(!query.matches(&question("evil.com", RecordType::A)));
+        assert!(!query.matches(&Question {
+            class: RecordClass::CH,
+            ..question("google.com", RecordType::A)
+        }));
+    }
 }
@@ -5,71 +5,264 @@ use crate::{
     fdbg,
 };
 
-use super::{header::Header, packet::Packet, question::Question};
-use std::net::{SocketAddr, UdpSocket};
+use super::{edns::UDP_PAYLOAD_SIZE, header::Header, packet::Packet, question::Question, tcp};
+use std::{
+    net::{SocketAddr, TcpStream, UdpSocket},
+    time::{Duration, Instant},
+};
+use tracing::debug;
+
+/// How long we wait for the upstream resolver to reply
+pub const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(5);
 
 pub struct DnsResolver {
     addr: String,
+    timeout: Duration,
 }
 
 impl DnsResolver {
     pub fn new(addr: String) -> Self {
-        Self { addr }
+        Self {
+            addr,
+            timeout: UPSTREAM_TIMEOUT,
+        }
+    }
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
     }
     /// Try to solve by creating new UdpSocket
-    pub fn resolve_with_new_socket(&self, packets: Vec<Packet>) -> Vec<Packet> {
-        let r_socket = UdpSocket::bind("0.0.0.0:0")
-            .context(fdbg!("Unable to bind UDP socket"))
-            .unwrap();
+    pub fn resolve_with_new_socket(&self, packets: Vec<Packet>) -> anyhow::Result<Vec<Packet>> {
+        // IPv6 resolvers need a socket of their own family
+        let local_addr = match self.addr.parse::<SocketAddr>() {
+            Ok(addr) if addr.is_ipv6() => "[::]:0",
+            _ => "0.0.0.0:0",
+        };
+        let r_socket = UdpSocket::bind(local_addr).context(fdbg!("Unable to bind UDP socket"))?;
+        r_socket
+            .set_read_timeout(Some(self.timeout))
+            .context(fdbg!("Unable to set UDP read timeout"))?;
         r_socket
             .connect(&self.addr)
-            .context(fdbg!("Unable to connect to resolver address"))
-            .unwrap();
-        let mut buf = [0; 512];
-        let packets = packets
+            .context(fdbg!("Unable to connect to resolver address"))?;
+        let mut buf = [0; UDP_PAYLOAD_SIZE as usize];
+        packets
             .iter()
             .map(|p| {
-                r_socket
-                    .send(&p.as_bytes())
-                    .context(fdbg!(
-                        "Unable to send to resolver address: {}",
-                        self.addr.clone()
-                    ))
-                    .unwrap();
+                r_socket.send(&p.as_bytes()).context(fdbg!(
+                    "Unable to send to resolver address: {}",
+                    self.addr.clone()
+                ))?;
                 let size = r_socket
                     .recv(&mut buf)
-                    .context(fdbg!("Unable to receive from resolver"))
-                    .unwrap();
-                let mut dns_reader = DnsReader::new(&buf);
-                let received_packet = Packet::parse(&mut dns_reader);
-                assert_eq!(p.header.id, received_packet.header.id);
-                received_packet
+                    .context(fdbg!("Unable to receive from resolver"))?;
+                self.retry_truncated(p, Self::read_reply(p, &buf[..size])?)
             })
-            .collect::<Vec<_>>();
-        return packets;
+            .collect()
     }
 
-    /// Try to resolve with existing socket
-    pub fn resolve(&self, socket: &UdpSocket, packets: Vec<Packet>) -> Vec<Packet> {
-        let mut buf = [0; 512];
-        let r_addr = &self.addr.parse::<SocketAddr>().unwrap();
-        let packets = packets
+    /// Try to resolve with existing socket, datagrams from anyone but the resolver are dropped
+    pub fn resolve(&self, socket: &UdpSocket, packets: Vec<Packet>) -> anyhow::Result<Vec<Packet>> {
+        let mut buf = [0; UDP_PAYLOAD_SIZE as usize];
+        let r_addr = &self
+            .addr
+            .parse::<SocketAddr>()
+            .context(fdbg!("Invalid resolver address: {}", self.addr))?;
+        packets
             .iter()
             .map(|p| {
                 socket
                     .send_to(&p.as_bytes(), r_addr)
-                    .context(fdbg!("Unable to send to resolver address"))
-                    .unwrap();
-                let size = socket
-                    .recv_from(&mut buf)
-                    .context(fdbg!("Unable to receive from resolver"))
-                    .unwrap();
-                let mut dns_reader = DnsReader::new(&buf);
-                let received_packet = Packet::parse(&mut dns_reader);
-                assert_eq!(p.header.id, received_packet.header.id);
-                received_packet
+                    .context(fdbg!("Unable to send to resolver address"))?;
+                let deadline = Instant::now() + self.timeout;
+                let size = loop {
+                    let (size, source) = socket
+                        .recv_from(&mut buf)
+                        .context(fdbg!("Unable to receive from resolver"))?;
+                    if source == *r_addr {
+                        break size;
+                    }
+                    debug!("Dropping datagram from {source}, waiting for resolver {r_addr}");
+                    if Instant::now() >= deadline {
+                        bail!(fdbg!("No reply from resolver {r_addr}"));
+                    }
+                };
+                self.retry_truncated(p, Self::read_reply(p, &buf[..size])?)
             })
-            .collect::<Vec<_>>();
-        return packets;
+            .collect()
+    }
+
+    /// Truncated UDP reply means the answer only fits over TCP, so ask the same question again
+    fn retry_truncated(&self, query: &Packet, reply: Packet) -> anyhow::Result<Packet> {
+        if reply.header.tc == 0 {
+            return Ok(reply);
+        }
+        debug!(
+            "Reply to {} is truncated, retrying over TCP",
+            query.header.id
+        );
+        self.resolve_over_tcp(query)
+    }
+
+    pub fn resolve_over_tcp(&self, query: &Packet) -> anyhow::Result<Packet> {
+        let mut stream = TcpStream::connect(&self.addr).context(fdbg!(
+            "Unable to connect to resolver address: {}",
+            self.addr
+        ))?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+        tcp::write_message(&mut stream, &query.as_bytes())
+            .context(fdbg!("Unable to send to resolver over TCP"))?;
+        let buf = tcp::read_message(&mut stream)
+            .context(fdbg!("Unable to receive from resolver over TCP"))?;
+        Self::read_reply(query, &buf)
+    }
+
+    fn read_reply(query: &Packet, buf: &[u8]) -> anyhow::Result<Packet> {
+        let mut dns_reader = DnsReader::new(buf);
+        let received_packet =
+            Packet::parse(&mut dns_reader).context(fdbg!("Unable to parse resolver response"))?;
+        if query.header.id != received_packet.header.id {
+            bail!(
+                "resolver replied with id {} to query {}",
+                received_packet.header.id,
+                query.header.id
+            );
+        }
+        // the id alone is easy to guess, a spoofed reply also has to get the question right
+        if received_packet.questions.len() != query.questions.len()
+            || !received_packet
+                .questions
+                .iter()
+                .zip(&query.questions)
+                .all(|(received, asked)| received.matches(asked))
+        {
+            bail!(
+                "resolver replied to query {} with a different question",
+                query.header.id
+            );
+        }
+        Ok(received_packet)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{Ipv4Addr, TcpListener, UdpSocket},
+        thread,
+        time::Duration,
+    };
+
+    use crate::{
+        common::{dns_reader::DnsReader, AsBytes, Parse},
+        dns::{
+            answer::{Answer, RData},
+            header::{Header, QueryResponse},
+            label::Label,
+            packet::Packet,
+            question::Question,
+            tcp, RecordClass, RecordType,
+        },
+    };
+
+    use super::DnsResolver;
+
+    fn reply(query: &Packet, answers: u8, tc: u8) -> Vec<u8> {
+        Packet::builder()
+            .header(Header {
+                qr: QueryResponse::Reply,
+                tc,
+                ..query.header.clone()
+            })
+            .questions(query.questions.clone())
+            .answers(
+                (0..answers)
+                    .map(|i| Answer {
+                        label: query.questions[0].name.clone(),
+                        typez: RecordType::A,
+                        class: RecordClass::IN,
+                        ttl: 60,
+                        rdata: RData::A(Ipv4Addr::new(10, 0, 0, i)),
+                    })
+                    .collect(),
+            )
+            .build()
+            .as_bytes()
+    }
+
+    #[test]
+    fn test_retry_truncated_over_tcp() {
+        let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = udp.local_addr().unwrap();
+        let tcp_listener = TcpListener::bind(addr).unwrap();
+        let upstream = thread::spawn(move || {
+            let mut buf = [0; 512];
+            let (size, source) = udp.recv_from(&mut buf).unwrap();
+            let query = Packet::parse(&mut DnsReader::new(&buf[..size])).unwrap();
+            udp.send_to(&reply(&query, 1, 1), source).unwrap();
+
+            let (mut stream, _) = tcp_listener.accept().unwrap();
+            let buf = tcp::read_message(&mut stream).unwrap();
+            let query = Packet::parse(&mut DnsReader::new(&buf)).unwrap();
+            tcp::write_message(&mut stream, &reply(&query, 50, 0)).unwrap();
+        });
+
+        let query = Packet::builder()
+            .header(Header {
+                id: 1234,
+                rd: 1,
+                ..Default::default()
+            })
+            .question(Question {
+                name: Label("codecrafters.io".to_string()),
+                typez: RecordType::A,
+                class: RecordClass::IN,
+            })
+            .build();
+        let resolved = DnsResolver::new(addr.to_string())
+            .resolve_with_new_socket(vec![query])
+            .unwrap();
+        upstream.join().unwrap();
+        assert_eq!(resolved[0].header.tc, 0);
+        assert_eq!(resolved[0].answers.len(), 50);
+    }
+
+    #[test]
+    fn test_shared_socket_drops_foreign_replies() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = upstream.local_addr().unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let local = socket.local_addr().unwrap();
+        let upstream = thread::spawn(move || {
+            let mut buf = [0; 512];
+            let (size, source) = upstream.recv_from(&mut buf).unwrap();
+            let query = Packet::parse(&mut DnsReader::new(&buf[..size])).unwrap();
+            // a guessed reply from someone else arrives first
+            let spoofer = UdpSocket::bind("127.0.0.1:0").unwrap();
+            spoofer.send_to(&reply(&query, 1, 0), local).unwrap();
+            upstream.send_to(&reply(&query, 2, 0), source).unwrap();
+        });
+
+        let query = Packet::builder()
+            .header(Header {
+                id: 4321,
+                rd: 1,
+                ..Default::default()
+            })
+            .question(Question {
+                name: Label("codecrafters.io".to_string()),
+                typez: RecordType::A,
+                class: RecordClass::IN,
+            })
+            .build();
+        let resolved = DnsResolver::new(addr.to_string())
+            .resolve(&socket, vec![query])
+            .unwrap();
+        upstream.join().unwrap();
+        assert_eq!(resolved[0].answers.len(), 2);
     }
 }
@@ -1,93 +1,445 @@
-use std::net::UdpSocket;
+use std::{
+    io::ErrorKind,
+    net::{SocketAddr, UdpSocket},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
 
-use anyhow::Context;
-use tracing::{debug, error, info};
+use anyhow::{bail, Context};
+use tracing::{debug, error, info, warn};
 
+use crate::dns::header::{Header, OpCode, QueryResponse, RCode};
 use crate::{
-    common::{AsBytes, dns_reader::DnsReader, Parse},
-    config::cli_args::CliArgs,
+    common::{dns_reader::DnsReader, AsBytes, Parse, ParseResult},
     dns::{
-        answer::{Answer, RData},
+        edns::{Edns, EDNS_VERSION},
         packet::Packet,
-        RecordClass,
-        RecordType, resolver::DnsResolver,
+        RecordType,
     },
     fdbg,
 };
-use crate::dns::header::Header;
 
-use super::packet::Merge;
+pub use self::{
+    middleware::{Middleware, Next},
+    request_handler::{ForwardHandler, MockHandler, RequestHandler, SwappableHandler},
+    server_builder::DnsServerBuilder,
+    server_handle::ServerHandle,
+};
+use self::{server_handle::RunningListener, tcp_listener::DnsTcpListener, worker_pool::WorkerPool};
 
-pub struct DnsServer {}
+pub mod middleware;
+pub mod request_handler;
+pub mod server_builder;
+pub mod server_handle;
+pub mod tcp_listener;
+pub mod worker_pool;
+
+/// How often the UDP listener stops waiting for a datagram to check if it should shut down
+const UDP_POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// Largest datagram UDP can carry, queries are read whole whatever size we advertise
+const MAX_UDP_DATAGRAM_SIZE: usize = u16::MAX as usize;
+
+/// How a query reached us
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    Udp,
+    Tcp,
+}
+
+/// Who sent a query and how, handed to the [`RequestHandler`] with every query
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientInfo {
+    pub source: SocketAddr,
+    pub transport: Transport,
+}
+
+/// Answers queries on every listener address over both UDP and TCP, built with
+/// `DnsServer::builder()`
+pub struct DnsServer {
+    listeners: Vec<SocketAddr>,
+    handler: Arc<dyn RequestHandler>,
+    workers: usize,
+    queue_size: usize,
+    tcp_idle_timeout: Duration,
+}
 
 impl DnsServer {
-    pub fn start(addr: &str) {
-        debug!("Starting DNS server at address: {addr}");
-        let socket = UdpSocket::bind(addr).expect("Failed to bind to address");
-        let mut buf = [0; 512];
-        loop {
+    /// Binds every listener and answers queries in the background until the returned handle is
+    /// shut down. Nothing is started when one of the addresses can't be bound
+    pub fn start(self) -> anyhow::Result<ServerHandle> {
+        if self.listeners.is_empty() {
+            bail!(fdbg!("No listener address configured"));
+        }
+        let bound = self
+            .listeners
+            .iter()
+            .map(|addr| Self::bind(*addr, self.tcp_idle_timeout))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let pool = Arc::new(WorkerPool::new(self.workers, self.queue_size));
+        let handler = Arc::new(SwappableHandler::new(self.handler));
+        let listeners = bound
+            .into_iter()
+            .map(|(socket, tcp_listener)| {
+                let addr = socket.local_addr()?;
+                let tcp_connections = tcp_listener.connections();
+                let tcp = {
+                    let stop = stop.clone();
+                    let pool = pool.clone();
+                    let handler = handler.clone();
+                    thread::spawn(move || tcp_listener.start(handler, pool, stop))
+                };
+                let udp = {
+                    let stop = stop.clone();
+                    let pool = pool.clone();
+                    let handler = handler.clone();
+                    thread::spawn(move || Self::serve_udp(Arc::new(socket), handler, &pool, &stop))
+                };
+                info!("DNS server is listening on {addr}");
+                Ok(RunningListener {
+                    addr,
+                    udp,
+                    tcp,
+                    tcp_connections,
+                })
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        Ok(ServerHandle {
+            listeners,
+            stop,
+            pool,
+            handler,
+        })
+    }
+
+    /// UDP and TCP on the same address. TCP goes on the port UDP got, they only differ when
+    /// port 0 was asked for
+    fn bind(
+        addr: SocketAddr,
+        tcp_idle_timeout: Duration,
+    ) -> anyhow::Result<(UdpSocket, DnsTcpListener)> {
+        debug!("Binding DNS server to address: {addr}");
+        let socket = UdpSocket::bind(addr).context(fdbg!("Failed to bind UDP to {addr}"))?;
+        socket
+            .set_read_timeout(Some(UDP_POLL_INTERVAL))
+            .context(fdbg!("Failed to set UDP read timeout"))?;
+        let udp_addr = socket.local_addr()?;
+        let tcp_listener = DnsTcpListener::bind(udp_addr)
+            .context(fdbg!("Failed to bind TCP to {udp_addr}"))?
+            .idle_timeout(tcp_idle_timeout);
+        Ok((socket, tcp_listener))
+    }
+
+    /// Reads datagrams and hands them to the worker pool, so a slow upstream only holds up
+    /// one worker instead of every client. Queries are dropped when the queue is full, the
+    /// client retries like it would for any lost datagram. Returns once `stop` is set, queries
+    /// still in flight are left to the pool
+    fn serve_udp(
+        socket: Arc<UdpSocket>,
+        handler: Arc<dyn RequestHandler>,
+        pool: &WorkerPool,
+        stop: &AtomicBool,
+    ) {
+        let mut buf = vec![0; MAX_UDP_DATAGRAM_SIZE];
+        while !stop.load(Ordering::SeqCst) {
             let (size, source) = match socket.recv_from(&mut buf) {
                 Ok((size, source)) => (size, source),
+                // read timeout, only there to check for `stop`
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    continue
+                }
                 Err(e) => {
                     error!("Error receiving data !!!, {e:#?}");
                     continue;
                 }
             };
-            let packet = Self::read_packet(&mut buf, size);
-            let response = Self::get_response_byte(&socket, packet);
-            socket
-                .send_to(&response, source)
-                .context(fdbg!("Failed to send response"))
-                .unwrap();
+            let query = buf[..size].to_vec();
+            let socket = socket.clone();
+            let handler = handler.clone();
+            let queued = pool.try_execute(move || {
+                let client = ClientInfo {
+                    source,
+                    transport: Transport::Udp,
+                };
+                let Some(response) = Self::handle_request(&*handler, &query, client) else {
+                    return;
+                };
+                if let Err(e) = socket
+                    .send_to(&response, source)
+                    .context(fdbg!("Failed to send response"))
+                {
+                    error!("{e:#}");
+                }
+            });
+            if !queued {
+                warn!("Worker queue is full, dropping query from {source}");
+            }
         }
+        debug!("UDP listener is stopped");
     }
 
-    fn get_response_byte(socket: &UdpSocket, packet: Packet) -> Vec<u8> {
-        match CliArgs::resolver() {
-            None => get_mock_response_byte(packet),
-            Some(addr) => {
-                let mut resolved = DnsResolver::new(addr)
-                    .resolve_with_new_socket(packet.split())
-                    .merge();
-                Packet::builder()
-                    .header(Header {
-                        qr: resolved.header.qr,
-                        ..packet.header.clone()
-                    })
-                    .questions(resolved.questions)
-                    .answers(resolved.answers)
-                    .build()
-                    .as_bytes()
+    /// Response bytes for a raw query, nothing is sent back when this returns None
+    pub fn handle_request(
+        handler: &dyn RequestHandler,
+        buf: &[u8],
+        client: ClientInfo,
+    ) -> Option<Vec<u8>> {
+        match Self::read_packet(buf) {
+            // never answer responses, that is how forwarding loops start
+            Ok(packet) if packet.header.qr == QueryResponse::Reply => {
+                warn!("Dropping response packet received from {}", client.source);
+                None
             }
+            Ok(packet) => {
+                let max_size = match client.transport {
+                    Transport::Udp => packet.max_udp_payload_size() as usize,
+                    Transport::Tcp => u16::MAX as usize,
+                };
+                Some(Self::get_response(handler, packet, &client).as_bytes_truncated(max_size))
+            }
+            Err(e) => {
+                error!("Unable to parse packet from {}, {e}", client.source);
+                Self::format_error_response(buf).map(|response| response.as_bytes())
+            }
+        }
+    }
+
+    fn get_response(handler: &dyn RequestHandler, packet: Packet, client: &ClientInfo) -> Packet {
+        if packet.header.opcode != OpCode::Query {
+            debug!("Opcode {:?} is not implemented", packet.header.opcode);
+            return Self::error_response(packet, RCode::NotImp);
+        }
+        if packet
+            .edns
+            .as_ref()
+            .is_some_and(|edns| edns.version != EDNS_VERSION)
+        {
+            return Self::error_response(packet, RCode::BadVers);
+        }
+        if packet.questions.is_empty() {
+            return Self::error_response(packet, RCode::FormErr);
+        }
+        if Self::is_refused(&packet) {
+            return Self::error_response(packet, RCode::Refused);
         }
+        handler.handle(packet, client)
     }
 
-    fn read_packet(buf: &mut [u8], packet_size: usize) -> Packet {
-        let mut dns_reader = DnsReader::new(&buf);
-        let packet = Packet::parse(&mut dns_reader);
-        tracing::debug!("Received packet: {packet:?}");
-        packet
+    /// Policy check, zone transfers are refused. Local zones are only answered one question at a
+    /// time by the zone middleware, which runs in the handler after this check
+    fn is_refused(packet: &Packet) -> bool {
+        packet.questions.iter().any(|q| q.typez == RecordType::AXFR)
     }
-}
 
-fn get_mock_response_byte(packet: Packet) -> Vec<u8> {
-    debug!("Sending mock response");
-    let packet = Packet::builder()
-        .header(packet.header.clone())
-        .answers(
-            packet
-                .questions
-                .iter()
-                .map(|q| Answer {
-                    label: q.name.clone(),
-                    typez: RecordType::A,
-                    class: RecordClass::IN,
-                    ttl: 60,
-                    rdata: RData("8.8.8.8".to_string()),
-                })
-                .collect(),
+    /// Reply with only the header and the questions of the query
+    fn error_response(packet: Packet, rcode: RCode) -> Packet {
+        Packet::builder()
+            .header(packet.header.reply(rcode))
+            .edns(packet.edns.as_ref().map(Edns::reply))
+            .questions(packet.questions)
+            .build()
+    }
+
+    /// FORMERR reply for a query we couldn't parse. The ID is echoed back when it can be read,
+    /// without it the client can't match the reply so nothing is sent
+    fn format_error_response(buf: &[u8]) -> Option<Packet> {
+        let mut dns_reader = DnsReader::new(buf);
+        let header = match Header::parse(&mut dns_reader) {
+            Ok(header) if header.qr == QueryResponse::Reply => return None,
+            Ok(header) => header,
+            Err(_) => Header {
+                id: DnsReader::new(buf).read_u16().ok()?,
+                ..Default::default()
+            },
+        };
+        Some(
+            Packet::builder()
+                .header(header.reply(RCode::FormErr))
+                .build(),
         )
-        .questions(packet.questions)
-        .build();
-    packet.as_bytes()
+    }
+
+    fn read_packet(buf: &[u8]) -> ParseResult<Packet> {
+        let mut dns_reader = DnsReader::new(buf);
+        let packet = Packet::parse(&mut dns_reader)?;
+        tracing::trace!("Received packet: {packet:?}");
+        Ok(packet)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashSet, net::UdpSocket, sync::Arc, thread, time::Duration};
+
+    use pretty_assertions::assert_eq;
+
+    use crate::{
+        common::{dns_reader::DnsReader, AsBytes, Parse},
+        dns::{
+            edns::{Edns, EdnsOption},
+            header::{Header, OpCode, QueryResponse, RCode},
+            label::Label,
+            packet::Packet,
+            question::Question,
+            RecordClass, RecordType,
+        },
+    };
+
+    use super::{worker_pool::WorkerPool, ClientInfo, DnsServer, MockHandler, Transport};
+
+    fn query(opcode: OpCode, typez: RecordType) -> Packet {
+        Packet::builder()
+            .header(Header {
+                id: 777,
+                opcode,
+                rd: 1,
+                ..Default::default()
+            })
+            .question(Question {
+                name: Label("codecrafters.io".to_string()),
+                typez,
+                class: RecordClass::IN,
+            })
+            .build()
+    }
+
+    fn get_response(packet: Packet) -> Packet {
+        let client = ClientInfo {
+            source: "127.0.0.1:5353".parse().unwrap(),
+            transport: Transport::Udp,
+        };
+        DnsServer::get_response(&MockHandler, packet, &client)
+    }
+
+    #[test]
+    fn test_not_implemented_opcode() {
+        let response = get_response(query(OpCode::IQuery, RecordType::A));
+        assert_eq!(response.header.id, 777);
+        assert_eq!(response.header.qr, QueryResponse::Reply);
+        assert_eq!(response.header.opcode, OpCode::IQuery);
+        assert_eq!(response.header.rcode, RCode::NotImp);
+        assert_eq!(response.header.qdcount, 1);
+        assert_eq!(response.header.ancount, 0);
+    }
+
+    #[test]
+    fn test_bad_edns_version() {
+        let mut packet = query(OpCode::Query, RecordType::A);
+        packet.edns = Some(Edns {
+            version: 1,
+            dnssec_ok: true,
+            ..Default::default()
+        });
+        let response = get_response(packet);
+        assert_eq!(response.header.rcode, RCode::BadVers);
+        let edns = response.edns.as_ref().unwrap();
+        assert_eq!(edns.version, 0);
+        assert!(edns.dnssec_ok);
+
+        let bytes = response.as_bytes();
+        // BADVERS is 16, so the header only has zeros for it
+        assert_eq!(bytes[3] & 0b1111, 0);
+        let parsed = Packet::parse(&mut DnsReader::new(&bytes)).unwrap();
+        assert_eq!(parsed.header.rcode, RCode::BadVers);
+        assert_eq!(parsed.header.arcount, 1);
+    }
+
+    #[test]
+    fn test_refused_zone_transfer() {
+        let response = get_response(query(OpCode::Query, RecordType::AXFR));
+        assert_eq!(response.header.rcode, RCode::Refused);
+    }
+
+    #[test]
+    fn test_format_error_echoes_id() {
+        // header claims one question but the packet ends right after it
+        let mut bytes = query(OpCode::Query, RecordType::A).as_bytes();
+        bytes.truncate(15);
+        assert!(DnsServer::read_packet(&bytes).is_err());
+        let response = DnsServer::format_error_response(&bytes).unwrap();
+        assert_eq!(response.header.id, 777);
+        assert_eq!(response.header.rd, 1);
+        assert_eq!(response.header.rcode, RCode::FormErr);
+        assert_eq!(response.header.qdcount, 0);
+
+        // only the id survived
+        let response = DnsServer::format_error_response(&[0x03, 0x09, 0x01]).unwrap();
+        assert_eq!(response.header.id, 777);
+        assert_eq!(response.header.rcode, RCode::FormErr);
+
+        // not even the id
+        assert!(DnsServer::format_error_response(&[0x03]).is_none());
+    }
+
+    #[test]
+    fn test_udp_queries_answered_by_pool() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        thread::spawn(move || {
+            DnsServer::serve_udp(
+                Arc::new(socket),
+                Arc::new(MockHandler),
+                &WorkerPool::new(2, 8),
+                &Default::default(),
+            )
+        });
+
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        for id in 1..=3 {
+            let mut query = query(OpCode::Status, RecordType::A);
+            query.header.id = id;
+            client.send_to(&query.as_bytes(), addr).unwrap();
+        }
+        let mut ids = HashSet::new();
+        let mut buf = [0; 512];
+        for _ in 1..=3 {
+            let size = client.recv(&mut buf).unwrap();
+            let response = Packet::parse(&mut DnsReader::new(&buf[..size])).unwrap();
+            assert_eq!(response.header.rcode, RCode::NotImp);
+            ids.insert(response.header.id);
+        }
+        assert_eq!(ids, HashSet::from([1, 2, 3]));
+    }
+
+    #[test]
+    fn test_udp_query_larger_than_payload_size() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        thread::spawn(move || {
+            DnsServer::serve_udp(
+                Arc::new(socket),
+                Arc::new(MockHandler),
+                &WorkerPool::new(1, 1),
+                &Default::default(),
+            )
+        });
+
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut query = query(OpCode::Status, RecordType::A);
+        query.edns = Some(Edns {
+            options: vec![EdnsOption {
+                code: 12,
+                data: vec![0; 2000],
+            }],
+            ..Default::default()
+        });
+        query.header.arcount = 1;
+        client.send_to(&query.as_bytes(), addr).unwrap();
+        let mut buf = [0; 512];
+        let size = client.recv(&mut buf).unwrap();
+        let response = Packet::parse(&mut DnsReader::new(&buf[..size])).unwrap();
+        // read whole, cut short it would have been a FORMERR
+        assert_eq!(response.header.rcode, RCode::NotImp);
+    }
 }
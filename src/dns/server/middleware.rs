@@ -0,0 +1,179 @@
+use std::sync::Arc;
+
+use crate::dns::packet::Packet;
+
+use super::{ClientInfo, RequestHandler};
+
+pub use self::{
+    acl::{AclMiddleware, IpNetwork},
+    block::BlockMiddleware,
+    log::LogMiddleware,
+    rate_limit::RateLimitMiddleware,
+    zone::ZoneMiddleware,
+};
+
+pub mod acl;
+pub mod block;
+pub mod log;
+pub mod rate_limit;
+pub mod zone;
+
+/// A layer around the [`RequestHandler`]. It gets the query first and can answer it right
+/// away, or call `next.run` to pass it down the chain and then look at or change the response
+pub trait Middleware: Send + Sync {
+    fn handle(&self, packet: Packet, client: &ClientInfo, next: Next<'_>) -> Packet;
+}
+
+/// Plain functions and closures work as middleware, handy for one-off rewrites
+impl<F> Middleware for F
+where
+    F: Fn(Packet, &ClientInfo, Next<'_>) -> Packet + Send + Sync,
+{
+    fn handle(&self, packet: Packet, client: &ClientInfo, next: Next<'_>) -> Packet {
+        self(packet, client, next)
+    }
+}
+
+/// The rest of the chain after the current middleware, the handler is at the end of it
+pub struct Next<'a> {
+    middlewares: &'a [Arc<dyn Middleware>],
+    handler: &'a dyn RequestHandler,
+}
+
+impl Next<'_> {
+    pub fn run(self, packet: Packet, client: &ClientInfo) -> Packet {
+        match self.middlewares.split_first() {
+            Some((middleware, middlewares)) => middleware.handle(
+                packet,
+                client,
+                Next {
+                    middlewares,
+                    handler: self.handler,
+                },
+            ),
+            None => self.handler.handle(packet, client),
+        }
+    }
+}
+
+/// Handler wrapped in middlewares, the first one is the outermost
+pub struct MiddlewareChain {
+    middlewares: Vec<Arc<dyn Middleware>>,
+    handler: Arc<dyn RequestHandler>,
+}
+
+impl MiddlewareChain {
+    pub fn new(middlewares: Vec<Arc<dyn Middleware>>, handler: Arc<dyn RequestHandler>) -> Self {
+        Self {
+            middlewares,
+            handler,
+        }
+    }
+}
+
+impl RequestHandler for MiddlewareChain {
+    fn handle(&self, packet: Packet, client: &ClientInfo) -> Packet {
+        Next {
+            middlewares: &self.middlewares,
+            handler: &*self.handler,
+        }
+        .run(packet, client)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use pretty_assertions::assert_eq;
+
+    use crate::dns::{
+        header::{Header, RCode},
+        label::Label,
+        packet::Packet,
+        question::Question,
+        server::{ClientInfo, MockHandler, RequestHandler, Transport},
+        RecordClass, RecordType,
+    };
+
+    use super::{Middleware, MiddlewareChain, Next};
+
+    pub(super) fn query(name: &str) -> Packet {
+        Packet::builder()
+            .header(Header {
+                id: 5,
+                ..Default::default()
+            })
+            .question(Question {
+                name: Label(name.to_string()),
+                typez: RecordType::A,
+                class: RecordClass::IN,
+            })
+            .build()
+    }
+
+    pub(super) fn client(source: &str) -> ClientInfo {
+        ClientInfo {
+            source: source.parse().unwrap(),
+            transport: Transport::Udp,
+        }
+    }
+
+    /// Records the order middlewares see the query and the response in
+    struct Trace(&'static str, Arc<Mutex<Vec<String>>>);
+
+    impl Middleware for Trace {
+        fn handle(&self, packet: Packet, client: &ClientInfo, next: Next<'_>) -> Packet {
+            self.1.lock().unwrap().push(format!("{} in", self.0));
+            let response = next.run(packet, client);
+            self.1.lock().unwrap().push(format!("{} out", self.0));
+            response
+        }
+    }
+
+    #[test]
+    fn test_chain_order() {
+        let trace = Arc::new(Mutex::new(vec![]));
+        let chain = MiddlewareChain::new(
+            vec![
+                Arc::new(Trace("outer", trace.clone())),
+                Arc::new(Trace("inner", trace.clone())),
+            ],
+            Arc::new(MockHandler),
+        );
+        let response = chain.handle(query("codecrafters.io"), &client("127.0.0.1:5353"));
+        assert_eq!(response.header.ancount, 1);
+        assert_eq!(
+            *trace.lock().unwrap(),
+            vec!["outer in", "inner in", "inner out", "outer out"]
+        );
+    }
+
+    #[test]
+    fn test_short_circuit_and_rewrite() {
+        let refuse_internal = |packet: Packet, client: &ClientInfo, next: Next<'_>| {
+            if packet.questions[0].name.0.ends_with(".internal") {
+                return Packet::builder()
+                    .header(packet.header.reply(RCode::Refused))
+                    .questions(packet.questions)
+                    .build();
+            }
+            let mut response = next.run(packet, client);
+            response
+                .answers
+                .iter_mut()
+                .for_each(|answer| answer.ttl = 5);
+            response
+        };
+        let chain = MiddlewareChain::new(vec![Arc::new(refuse_internal)], Arc::new(MockHandler));
+        let client = client("127.0.0.1:5353");
+
+        let response = chain.handle(query("db.internal"), &client);
+        assert_eq!(response.header.rcode, RCode::Refused);
+        assert_eq!(response.header.ancount, 0);
+
+        let response = chain.handle(query("codecrafters.io"), &client);
+        assert_eq!(response.header.rcode, RCode::NoError);
+        assert_eq!(response.answers[0].ttl, 5);
+    }
+}
@@ -0,0 +1,155 @@
+use std::{net::IpAddr, str::FromStr};
+
+use tracing::debug;
+
+use crate::dns::{header::RCode, packet::Packet, server::ClientInfo, server::DnsServer};
+
+use super::{Middleware, Next};
+
+/// Address range in CIDR notation, e.g. `10.0.0.0/8` or `::1/128`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpNetwork {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpNetwork {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // IPv4 clients on a dual stack socket show up as IPv4 mapped IPv6 addresses
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+            ip => ip,
+        };
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => prefix_matches(
+                u32::from(net) as u128,
+                u32::from(ip) as u128,
+                32,
+                self.prefix,
+            ),
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                prefix_matches(u128::from(net), u128::from(ip), 128, self.prefix)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn prefix_matches(net: u128, ip: u128, bits: u8, prefix: u8) -> bool {
+    if prefix == 0 {
+        return true;
+    }
+    let shift = bits - prefix;
+    net >> shift == ip >> shift
+}
+
+/// A bare address is a network with only that address in it
+impl FromStr for IpNetwork {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr = addr
+            .parse::<IpAddr>()
+            .map_err(|_| "expected an IP address with an optional prefix length".to_string())?;
+        let max_prefix = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse::<u8>()
+                .map_err(|_| format!("invalid prefix length '{prefix}'"))?,
+            None => max_prefix,
+        };
+        if prefix > max_prefix {
+            return Err(format!("prefix length is over {max_prefix}"));
+        }
+        Ok(Self { addr, prefix })
+    }
+}
+
+/// Answers REFUSED to clients outside of the allowed networks
+pub struct AclMiddleware {
+    allowed: Vec<IpNetwork>,
+}
+
+impl AclMiddleware {
+    pub fn allow(allowed: Vec<IpNetwork>) -> Self {
+        Self { allowed }
+    }
+}
+
+impl Middleware for AclMiddleware {
+    fn handle(&self, packet: Packet, client: &ClientInfo, next: Next<'_>) -> Packet {
+        let ip = client.source.ip();
+        if !self.allowed.iter().any(|network| network.contains(ip)) {
+            debug!("Refusing query from {ip}, not in the allowed networks");
+            return DnsServer::error_response(packet, RCode::Refused);
+        }
+        next.run(packet, client)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use pretty_assertions::assert_eq;
+
+    use crate::dns::{
+        header::RCode,
+        server::{
+            middleware::{
+                tests::{client, query},
+                MiddlewareChain,
+            },
+            MockHandler, RequestHandler,
+        },
+    };
+
+    use super::{AclMiddleware, IpNetwork};
+
+    #[test]
+    fn test_ip_network() {
+        let network = "10.1.0.0/16".parse::<IpNetwork>().unwrap();
+        assert!(network.contains("10.1.200.3".parse().unwrap()));
+        assert!(!network.contains("10.2.0.1".parse().unwrap()));
+        assert!(network.contains("::ffff:10.1.0.1".parse().unwrap()));
+
+        let network = "fd00::/8".parse::<IpNetwork>().unwrap();
+        assert!(network.contains("fd12::1".parse().unwrap()));
+        assert!(!network.contains("fe80::1".parse().unwrap()));
+        assert!(!network.contains("10.1.0.1".parse().unwrap()));
+
+        assert!("0.0.0.0/0"
+            .parse::<IpNetwork>()
+            .unwrap()
+            .contains("1.2.3.4".parse().unwrap()));
+        assert!("127.0.0.1"
+            .parse::<IpNetwork>()
+            .unwrap()
+            .contains("127.0.0.1".parse().unwrap()));
+
+        assert!("10.0.0.0/33".parse::<IpNetwork>().is_err());
+        assert!("10.0.0/8".parse::<IpNetwork>().is_err());
+        assert!("10.0.0.0/x".parse::<IpNetwork>().is_err());
+    }
+
+    #[test]
+    fn test_acl() {
+        let chain = MiddlewareChain::new(
+            vec![Arc::new(AclMiddleware::allow(vec!["192.168.0.0/24"
+                .parse()
+                .unwrap()]))],
+            Arc::new(MockHandler),
+        );
+        let response = chain.handle(query("codecrafters.io"), &client("192.168.0.10:5353"));
+        assert_eq!(response.header.rcode, RCode::NoError);
+        assert_eq!(response.header.ancount, 1);
+
+        let response = chain.handle(query("codecrafters.io"), &client("192.168.1.10:5353"));
+        assert_eq!(response.header.rcode, RCode::Refused);
+        assert_eq!(response.header.ancount, 0);
+    }
+}
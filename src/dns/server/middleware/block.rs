@@ -0,0 +1,86 @@
+use tracing::debug;
+
+use crate::dns::{
+    header::RCode,
+    packet::Packet,
+    server::{ClientInfo, DnsServer},
+};
+
+use super::{Middleware, Next};
+
+/// Answers NXDOMAIN for the blocked names and every name below them
+pub struct BlockMiddleware {
+    names: Vec<String>,
+}
+
+impl BlockMiddleware {
+    pub fn new(names: Vec<String>) -> Self {
+        Self {
+            names: names.iter().map(|name| normalize(name)).collect(),
+        }
+    }
+
+    fn is_blocked(&self, name: &str) -> bool {
+        let name = normalize(name);
+        self.names
+            .iter()
+            .any(|blocked| name == *blocked || name.ends_with(&format!(".{blocked}")))
+    }
+}
+
+fn normalize(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+impl Middleware for BlockMiddleware {
+    fn handle(&self, packet: Packet, client: &ClientInfo, next: Next<'_>) -> Packet {
+        if let Some(question) = packet.questions.iter().find(|q| self.is_blocked(&q.name.0)) {
+            debug!("{} is blocked", question.name);
+            return DnsServer::error_response(packet, RCode::NXDomain);
+        }
+        next.run(packet, client)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use pretty_assertions::assert_eq;
+
+    use crate::dns::{
+        header::RCode,
+        server::{
+            middleware::{
+                tests::{client, query},
+                MiddlewareChain,
+            },
+            MockHandler, RequestHandler,
+        },
+    };
+
+    use super::BlockMiddleware;
+
+    #[test]
+    fn test_blocked_names() {
+        let chain = MiddlewareChain::new(
+            vec![Arc::new(BlockMiddleware::new(vec![
+                "Ads.Example.com.".to_string()
+            ]))],
+            Arc::new(MockHandler),
+        );
+        let client = client("127.0.0.1:5353");
+        for name in ["ads.example.com", "x.ads.EXAMPLE.com"] {
+            assert_eq!(
+                chain.handle(query(name), &client).header.rcode,
+                RCode::NXDomain
+            );
+        }
+        for name in ["example.com", "badads.example.com"] {
+            assert_eq!(
+                chain.handle(query(name), &client).header.rcode,
+                RCode::NoError
+            );
+        }
+    }
+}
@@ -0,0 +1,36 @@
+use std::time::Instant;
+
+use tracing::{debug, enabled, Level};
+
+use crate::dns::{packet::Packet, server::ClientInfo};
+
+use super::{Middleware, Next};
+
+/// One line per query with the client, the questions, the outcome and how long it took.
+/// Logged at debug, busy servers would drown everything else at info
+pub struct LogMiddleware;
+
+impl Middleware for LogMiddleware {
+    fn handle(&self, packet: Packet, client: &ClientInfo, next: Next<'_>) -> Packet {
+        if !enabled!(Level::DEBUG) {
+            return next.run(packet, client);
+        }
+        let started = Instant::now();
+        let questions = packet
+            .questions
+            .iter()
+            .map(|q| format!("{} {} {}", q.name, q.class, q.typez))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let response = next.run(packet, client);
+        debug!(
+            "{} {:?} {questions} -> {:?} with {} answers in {:?}",
+            client.source,
+            client.transport,
+            response.header.rcode,
+            response.answers.len(),
+            started.elapsed()
+        );
+        response
+    }
+}
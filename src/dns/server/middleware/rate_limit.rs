@@ -0,0 +1,138 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::IpAddr,
+    sync::Mutex,
+    time::Instant,
+};
+
+use tracing::debug;
+
+use crate::dns::{header::RCode, packet::Packet, server::ClientInfo, server::DnsServer};
+
+use super::{Middleware, Next};
+
+/// Clients tracked at once, the least recently seen one is forgotten when there are more
+const MAX_TRACKED_CLIENTS: usize = 10_000;
+
+/// Token bucket per client address, `burst` queries at once and `per_second` after that.
+/// Queries over the limit are answered with REFUSED
+pub struct RateLimitMiddleware {
+    per_second: f64,
+    burst: f64,
+    max_clients: usize,
+    buckets: Mutex<Buckets>,
+}
+
+#[derive(Default)]
+struct Buckets {
+    clients: HashMap<IpAddr, Bucket>,
+    /// Clients by last use, the first one is the next to go
+    lru: BTreeMap<u64, IpAddr>,
+    next_use: u64,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    /// Key in [`Buckets::lru`]
+    used: u64,
+}
+
+impl RateLimitMiddleware {
+    pub fn new(per_second: u32, burst: u32) -> Self {
+        Self {
+            per_second: per_second as f64,
+            burst: burst.max(1) as f64,
+            max_clients: MAX_TRACKED_CLIENTS,
+            buckets: Mutex::default(),
+        }
+    }
+
+    fn allow(&self, ip: IpAddr, now: Instant) -> bool {
+        let mut buckets = self.buckets.lock().expect("Rate limit lock is poisoned");
+        let Buckets {
+            clients,
+            lru,
+            next_use,
+        } = &mut *buckets;
+        if clients.len() >= self.max_clients && !clients.contains_key(&ip) {
+            if let Some((_, oldest)) = lru.pop_first() {
+                clients.remove(&oldest);
+            }
+        }
+        let used = *next_use;
+        *next_use += 1;
+        let bucket = clients.entry(ip).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+            used,
+        });
+        lru.remove(&bucket.used);
+        lru.insert(used, ip);
+        bucket.used = used;
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.per_second).min(self.burst);
+        bucket.updated = now;
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        true
+    }
+}
+
+impl Middleware for RateLimitMiddleware {
+    fn handle(&self, packet: Packet, client: &ClientInfo, next: Next<'_>) -> Packet {
+        let ip = client.source.ip();
+        if !self.allow(ip, Instant::now()) {
+            debug!("Rate limit exceeded for {ip}");
+            return DnsServer::error_response(packet, RCode::Refused);
+        }
+        next.run(packet, client)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::RateLimitMiddleware;
+
+    #[test]
+    fn test_token_bucket() {
+        let limiter = RateLimitMiddleware::new(10, 3);
+        let ip = "10.0.0.1".parse().unwrap();
+        let other = "10.0.0.2".parse().unwrap();
+        let now = Instant::now();
+        assert!(limiter.allow(ip, now));
+        assert!(limiter.allow(ip, now));
+        assert!(limiter.allow(ip, now));
+        assert!(!limiter.allow(ip, now));
+        // every client has its own bucket
+        assert!(limiter.allow(other, now));
+        // 10 per second is one token every 100ms
+        assert!(limiter.allow(ip, now + Duration::from_millis(100)));
+        assert!(!limiter.allow(ip, now + Duration::from_millis(150)));
+        // never more than the burst
+        let later = now + Duration::from_secs(60);
+        assert!((0..3).all(|_| limiter.allow(ip, later)));
+        assert!(!limiter.allow(ip, later));
+    }
+
+    #[test]
+    fn test_least_recently_seen_client_is_forgotten() {
+        let mut limiter = RateLimitMiddleware::new(1, 1);
+        limiter.max_clients = 2;
+        let [a, b, c] = ["10.0.0.1", "10.0.0.2", "10.0.0.3"].map(|ip| ip.parse().unwrap());
+        let now = Instant::now();
+        assert!(limiter.allow(a, now));
+        assert!(limiter.allow(b, now));
+        assert!(!limiter.allow(a, now));
+        // b is the least recently seen, it goes to make room for c
+        assert!(limiter.allow(c, now));
+        assert_eq!(limiter.buckets.lock().unwrap().clients.len(), 2);
+        assert!(!limiter.allow(a, now));
+        assert!(limiter.allow(b, now));
+        assert_eq!(limiter.buckets.lock().unwrap().lru.len(), 2);
+    }
+}
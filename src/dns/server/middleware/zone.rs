@@ -0,0 +1,114 @@
+use tracing::debug;
+
+use crate::dns::{edns::Edns, header::RCode, packet::Packet, server::ClientInfo, zone::Zone};
+
+use super::{Middleware, Next};
+
+/// Answers with authority for names in the local zones, everything else goes down the chain
+pub struct ZoneMiddleware {
+    zones: Vec<Zone>,
+}
+
+impl ZoneMiddleware {
+    pub fn new(zones: Vec<Zone>) -> Self {
+        Self { zones }
+    }
+
+    /// Most specific zone wins when zones are nested
+    fn zone_of(&self, packet: &Packet) -> Option<&Zone> {
+        let question = packet.questions.first()?;
+        self.zones
+            .iter()
+            .filter(|zone| zone.contains(&question.name))
+            .max_by_key(|zone| zone.name.0.len())
+    }
+}
+
+impl Middleware for ZoneMiddleware {
+    fn handle(&self, packet: Packet, client: &ClientInfo, next: Next<'_>) -> Packet {
+        let Some(zone) = self.zone_of(&packet) else {
+            return next.run(packet, client);
+        };
+        let question = &packet.questions[0];
+        let (rcode, answers) = match zone.lookup(&question.name, &question.typez) {
+            Some(answers) => (RCode::NoError, answers),
+            None => {
+                debug!("{} does not exist in zone {}", question.name, zone.name);
+                (RCode::NXDomain, vec![])
+            }
+        };
+        // negative answers carry the SOA so they can be cached
+        // https://www.rfc-editor.org/rfc/rfc2308#section-3
+        let authorities = match answers.is_empty() {
+            true => vec![zone.soa.clone()],
+            false => vec![],
+        };
+        let mut header = packet.header.reply(rcode);
+        header.aa = 1;
+        Packet::builder()
+            .header(header)
+            .answers(answers)
+            .authorities(authorities)
+            .edns(packet.edns.as_ref().map(Edns::reply))
+            .questions(packet.questions)
+            .build()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use pretty_assertions::assert_eq;
+
+    use crate::dns::{
+        header::RCode,
+        server::{
+            middleware::{
+                tests::{client, query},
+                MiddlewareChain,
+            },
+            MockHandler, RequestHandler,
+        },
+        zone::Zone,
+        RecordType,
+    };
+
+    use super::ZoneMiddleware;
+
+    #[test]
+    fn test_zone_answers() {
+        let mut zone = Zone::new("home.lan").unwrap();
+        zone.add_record("nas", "A", 300, "192.168.1.10").unwrap();
+        zone.add_record("tv.media", "A", 300, "192.168.1.20")
+            .unwrap();
+        let chain = MiddlewareChain::new(
+            vec![Arc::new(ZoneMiddleware::new(vec![zone]))],
+            Arc::new(MockHandler),
+        );
+        let client = client("127.0.0.1:5353");
+
+        let response = chain.handle(query("nas.home.lan"), &client);
+        assert_eq!(response.header.rcode, RCode::NoError);
+        assert_eq!(response.header.aa, 1);
+        assert_eq!(response.answers[0].ttl, 300);
+
+        let response = chain.handle(query("tv.home.lan"), &client);
+        assert_eq!(response.header.rcode, RCode::NXDomain);
+        assert_eq!(response.header.aa, 1);
+        assert!(response.answers.is_empty());
+        assert_eq!(response.authorities[0].typez, RecordType::SOA);
+        assert_eq!(response.authorities[0].label.0, "home.lan");
+
+        // empty non-terminal, the name exists without records of its own
+        let response = chain.handle(query("media.home.lan"), &client);
+        assert_eq!(response.header.rcode, RCode::NoError);
+        assert!(response.answers.is_empty());
+        assert_eq!(response.authorities[0].typez, RecordType::SOA);
+
+        // not ours, the mock handler answers
+        let response = chain.handle(query("codecrafters.io"), &client);
+        assert_eq!(response.header.aa, 0);
+        assert_eq!(response.answers[0].ttl, 60);
+    }
+}
@@ -0,0 +1,514 @@
+use std::{
+    net::Ipv4Addr,
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
+
+use tracing::{debug, error, warn};
+
+use crate::dns::{
+    answer::{Answer, RData},
+    cache::DnsCache,
+    edns::Edns,
+    header::{Header, QueryResponse, RCode},
+    packet::{Merge, Packet},
+    resolver::DnsResolver,
+    RecordClass, RecordType,
+};
+
+use super::{worker_pool::WorkerPool, ClientInfo, DnsServer};
+
+/// Threads refreshing popular cached responses, a few are enough as the clients already have
+/// their answers
+pub const PREFETCH_WORKERS: usize = 2;
+/// Refreshes waiting for a free prefetch thread, anything above that is skipped
+pub const PREFETCH_QUEUE_SIZE: usize = 32;
+
+/// Produces the response for a query. The server only hands over queries it is able to answer,
+/// i.e. standard queries with at least one question, and takes care of the wire format
+pub trait RequestHandler: Send + Sync {
+    fn handle(&self, packet: Packet, client: &ClientInfo) -> Packet;
+}
+
+/// Answers every question with an A record for 8.8.8.8, used when no resolver is configured
+pub struct MockHandler;
+
+impl RequestHandler for MockHandler {
+    fn handle(&self, packet: Packet, _client: &ClientInfo) -> Packet {
+        debug!("Sending mock response");
+        Packet::builder()
+            .header(Header {
+                qr: QueryResponse::Reply,
+                ..packet.header.clone()
+            })
+            .answers(
+                packet
+                    .questions
+                    .iter()
+                    .map(|q| Answer {
+                        label: q.name.clone(),
+                        typez: RecordType::A,
+                        class: RecordClass::IN,
+                        ttl: 60,
+                        rdata: RData::A(Ipv4Addr::new(8, 8, 8, 8)),
+                    })
+                    .collect(),
+            )
+            .edns(packet.edns.as_ref().map(Edns::reply))
+            .questions(packet.questions)
+            .build()
+    }
+}
+
+/// Sends every question to the upstream resolvers, the next one is tried when one fails
+pub struct ForwardHandler {
+    /// Shared with the threads prefetching into the cache
+    resolvers: Arc<[DnsResolver]>,
+    cache: Option<Arc<DnsCache>>,
+    /// Only there with a cache to prefetch into, shared like the cache so replacing the handler
+    /// never waits for a running refresh
+    prefetch: Option<Arc<WorkerPool>>,
+}
+
+impl ForwardHandler {
+    pub fn new(addrs: Vec<String>, timeout: Duration) -> Self {
+        Self {
+            resolvers: addrs
+                .into_iter()
+                .map(|addr| DnsResolver::new(addr).timeout(timeout))
+                .collect(),
+            cache: None,
+            prefetch: None,
+        }
+    }
+
+    /// Answers from `cache` while they are fresh and refreshes popular entries on `prefetch`.
+    /// Both can outlive the handler
+    pub fn cache(mut self, cache: Arc<DnsCache>, prefetch: Arc<WorkerPool>) -> Self {
+        self.cache = Some(cache);
+        self.prefetch = Some(prefetch);
+        self
+    }
+
+    fn resolve(&self, packet: &Packet) -> Option<Packet> {
+        packet
+            .split()
+            .into_iter()
+            .map(|query| self.resolve_question(query))
+            .collect::<Option<Vec<_>>>()
+            .map(|resolved| resolved.merge())
+    }
+
+    fn resolve_question(&self, query: Packet) -> Option<Packet> {
+        let now = Instant::now();
+        if let Some(cache) = &self.cache {
+            if let Some(cached) = cache.get(&query, now) {
+                debug!("Answering {} from the cache", query.questions[0].name);
+                if cache.claim_prefetch(&query, now) {
+                    self.prefetch(query, cache.clone());
+                }
+                return Some(cached);
+            }
+        }
+        let resolved = match forward(&self.resolvers, &query) {
+            Some(resolved) if resolved.header.rcode != RCode::ServFail => resolved,
+            failed => {
+                // better an answer that may be out of date than none at all
+                let stale = self
+                    .cache
+                    .as_ref()
+                    .and_then(|cache| cache.get_stale(&query, now));
+                if stale.is_some() {
+                    warn!(
+                        "Upstream failed, answering {} with stale data",
+                        query.questions[0].name
+                    );
+                }
+                return stale.or(failed);
+            }
+        };
+        if let Some(cache) = &self.cache {
+            cache.insert(&query, &resolved, now);
+        }
+        Some(resolved)
+    }
+
+    /// Refreshes a popular cached response before it expires, the client already has its answer.
+    /// Skipped when the prefetch queue is full, the entry runs out as usual then
+    fn prefetch(&self, query: Packet, cache: Arc<DnsCache>) {
+        let Some(pool) = &self.prefetch else {
+            return;
+        };
+        debug!("Prefetching {}", query.questions[0].name);
+        let name = query.questions[0].name.clone();
+        let resolvers = self.resolvers.clone();
+        let claim = PrefetchClaim { query, cache };
+        // a job that is not queued is dropped right away, and with it the claim
+        let queued = pool.try_execute(move || {
+            let PrefetchClaim { query, cache } = &claim;
+            match forward(&resolvers, query) {
+                Some(resolved) if resolved.header.rcode != RCode::ServFail => {
+                    cache.insert(query, &resolved, Instant::now());
+                }
+                _ => warn!("Unable to prefetch {}", query.questions[0].name),
+            }
+        });
+        if !queued {
+            debug!("Prefetch queue is full, skipping {name}");
+        }
+    }
+}
+
+/// Released when the prefetch is done, skipped or panics. A refreshed entry starts unclaimed,
+/// otherwise the next hit can try again
+struct PrefetchClaim {
+    query: Packet,
+    cache: Arc<DnsCache>,
+}
+
+impl Drop for PrefetchClaim {
+    fn drop(&mut self) {
+        self.cache.release_prefetch(&self.query);
+    }
+}
+
+fn forward(resolvers: &[DnsResolver], query: &Packet) -> Option<Packet> {
+    for resolver in resolvers {
+        match resolver.resolve_with_new_socket(vec![query.clone()]) {
+            Ok(mut resolved) => return resolved.pop(),
+            Err(e) => warn!("Unable to resolve query {}, {e:#}", query.header.id),
+        }
+    }
+    None
+}
+
+impl RequestHandler for ForwardHandler {
+    fn handle(&self, packet: Packet, _client: &ClientInfo) -> Packet {
+        let Some(resolved) = self.resolve(&packet) else {
+            error!("No resolver could answer query {}", packet.header.id);
+            return DnsServer::error_response(packet, RCode::ServFail);
+        };
+        Packet::builder()
+            .header(Header {
+                qr: resolved.header.qr,
+                rcode: resolved.header.rcode,
+                ..packet.header.clone()
+            })
+            .questions(resolved.questions)
+            .answers(resolved.answers)
+            .authorities(resolved.authorities)
+            .additionals(resolved.additionals)
+            .edns(packet.edns.as_ref().map(Edns::reply))
+            .build()
+    }
+}
+
+/// Handler that can be replaced while the server runs. Queries already being answered finish
+/// with the handler they started with, the next ones get the new handler
+pub struct SwappableHandler {
+    current: RwLock<Arc<dyn RequestHandler>>,
+}
+
+impl SwappableHandler {
+    pub fn new(handler: Arc<dyn RequestHandler>) -> Self {
+        Self {
+            current: RwLock::new(handler),
+        }
+    }
+
+    /// Replaces the handler and returns the one that was in use
+    pub fn swap(&self, handler: Arc<dyn RequestHandler>) -> Arc<dyn RequestHandler> {
+        let mut current = self.current.write().expect("Handler lock is poisoned");
+        std::mem::replace(&mut *current, handler)
+    }
+
+    fn current(&self) -> Arc<dyn RequestHandler> {
+        self.current
+            .read()
+            .expect("Handler lock is poisoned")
+            .clone()
+    }
+}
+
+impl RequestHandler for SwappableHandler {
+    fn handle(&self, packet: Packet, client: &ClientInfo) -> Packet {
+        // the lock is only held to clone the handler, not while answering
+        self.current().handle(packet, client)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{Ipv4Addr, UdpSocket},
+        sync::Arc,
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use pretty_assertions::assert_eq;
+
+    use crate::{
+        common::{dns_reader::DnsReader, AsBytes, Parse},
+        dns::{
+            answer::{Answer, RData},
+            cache::DnsCache,
+            header::{Header, QueryResponse, RCode},
+            label::Label,
+            packet::Packet,
+            question::Question,
+            server::{ClientInfo, Transport},
+            RecordClass, RecordType,
+        },
+    };
+
+    use super::{
+        ForwardHandler, MockHandler, PrefetchClaim, RequestHandler, SwappableHandler, WorkerPool,
+        PREFETCH_QUEUE_SIZE, PREFETCH_WORKERS,
+    };
+
+    fn query() -> Packet {
+        Packet::builder()
+            .header(Header {
+                id: 99,
+                rd: 1,
+                ..Default::default()
+            })
+            .question(Question {
+                name: Label("codecrafters.io".to_string()),
+                typez: RecordType::A,
+                class: RecordClass::IN,
+            })
+            .build()
+    }
+
+    fn prefetch() -> Arc<WorkerPool> {
+        Arc::new(WorkerPool::new(PREFETCH_WORKERS, PREFETCH_QUEUE_SIZE))
+    }
+
+    fn client() -> ClientInfo {
+        ClientInfo {
+            source: "127.0.0.1:5353".parse().unwrap(),
+            transport: Transport::Udp,
+        }
+    }
+
+    #[test]
+    fn test_mock_handler() {
+        let response = MockHandler.handle(query(), &client());
+        assert_eq!(response.header.id, 99);
+        assert_eq!(response.header.qr, QueryResponse::Reply);
+        assert_eq!(response.header.ancount, 1);
+        assert_eq!(
+            response.answers[0].rdata,
+            RData::A(Ipv4Addr::new(8, 8, 8, 8))
+        );
+    }
+
+    #[test]
+    fn test_forward_handler_unreachable_upstream() {
+        // nothing to forward to, the address can't even be connected to
+        let response =
+            ForwardHandler::new(vec!["not an address".to_string()], Duration::from_secs(1))
+                .handle(query(), &client());
+        assert_eq!(response.header.id, 99);
+        assert_eq!(response.header.rcode, RCode::ServFail);
+        assert_eq!(response.header.qdcount, 1);
+    }
+
+    #[test]
+    fn test_forward_handler_answers_from_cache() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = upstream.local_addr().unwrap().to_string();
+        // answers a single query, anything after that has to come from the cache
+        let upstream = thread::spawn(move || {
+            let mut buf = [0; 512];
+            let (size, source) = upstream.recv_from(&mut buf).unwrap();
+            let query = Packet::parse(&mut DnsReader::new(&buf[..size])).unwrap();
+            let reply = Packet::builder()
+                .header(Header {
+                    qr: QueryResponse::Reply,
+                    ..query.header.clone()
+                })
+                .answer(Answer {
+                    label: query.questions[0].name.clone(),
+                    typez: RecordType::A,
+                    class: RecordClass::IN,
+                    ttl: 300,
+                    rdata: RData::A(Ipv4Addr::new(10, 0, 0, 1)),
+                })
+                .questions(query.questions)
+                .build();
+            upstream.send_to(&reply.as_bytes(), source).unwrap();
+        });
+
+        let cache = Arc::new(DnsCache::new(10));
+        let handler = ForwardHandler::new(vec![addr], Duration::from_millis(500))
+            .cache(cache.clone(), prefetch());
+        let response = handler.handle(query(), &client());
+        upstream.join().unwrap();
+        assert_eq!(
+            response.answers[0].rdata,
+            RData::A(Ipv4Addr::new(10, 0, 0, 1))
+        );
+        assert_eq!(cache.len(), 1);
+
+        let response = handler.handle(query(), &client());
+        assert_eq!(response.header.id, 99);
+        assert_eq!(response.header.rcode, RCode::NoError);
+        assert_eq!(response.answers[0].ttl, 300);
+    }
+
+    #[test]
+    fn test_forward_handler_rejects_other_question() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = upstream.local_addr().unwrap().to_string();
+        let upstream = thread::spawn(move || {
+            let mut buf = [0; 512];
+            let (size, source) = upstream.recv_from(&mut buf).unwrap();
+            let query = Packet::parse(&mut DnsReader::new(&buf[..size])).unwrap();
+            // right id, but the answer is for a name nobody asked about
+            let name = Label("evil.com".to_string());
+            let reply = Packet::builder()
+                .header(Header {
+                    qr: QueryResponse::Reply,
+                    ..query.header.clone()
+                })
+                .question(Question {
+                    name: name.clone(),
+                    typez: RecordType::A,
+                    class: RecordClass::IN,
+                })
+                .answer(Answer {
+                    label: name,
+                    typez: RecordType::A,
+                    class: RecordClass::IN,
+                    ttl: 300,
+                    rdata: RData::A(Ipv4Addr::new(6, 6, 6, 6)),
+                })
+                .build();
+            upstream.send_to(&reply.as_bytes(), source).unwrap();
+        });
+
+        let cache = Arc::new(DnsCache::new(10));
+        let response = ForwardHandler::new(vec![addr], Duration::from_millis(500))
+            .cache(cache.clone(), prefetch())
+            .handle(query(), &client());
+        upstream.join().unwrap();
+        assert_eq!(response.header.rcode, RCode::ServFail);
+        assert!(response.answers.is_empty());
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn test_forward_handler_serves_stale() {
+        let cache = Arc::new(DnsCache::new(10).stale_window(Duration::from_secs(3600)));
+        let stored = Instant::now()
+            .checked_sub(Duration::from_secs(600))
+            .unwrap();
+        let answer = Packet::builder()
+            .header(Header {
+                qr: QueryResponse::Reply,
+                ..Default::default()
+            })
+            .questions(query().questions)
+            .answer(Answer {
+                label: Label("codecrafters.io".to_string()),
+                typez: RecordType::A,
+                class: RecordClass::IN,
+                ttl: 300,
+                rdata: RData::A(Ipv4Addr::new(10, 0, 0, 1)),
+            })
+            .build();
+        cache.insert(&query(), &answer, stored);
+
+        let response =
+            ForwardHandler::new(vec!["not an address".to_string()], Duration::from_secs(1))
+                .cache(cache, prefetch())
+                .handle(query(), &client());
+        assert_eq!(response.header.rcode, RCode::NoError);
+        assert_eq!(response.answers[0].ttl, 30);
+    }
+
+    #[test]
+    fn test_failed_prefetch_can_be_retried() {
+        let cache = Arc::new(DnsCache::new(10));
+        insert_expiring(&cache);
+
+        let handler =
+            ForwardHandler::new(vec!["not an address".to_string()], Duration::from_secs(1))
+                .cache(cache.clone(), prefetch());
+        // the third hit starts a prefetch, which fails
+        for _ in 0..3 {
+            assert_eq!(handler.handle(query(), &client()).answers.len(), 1);
+        }
+        let deadline = Instant::now() + Duration::from_secs(2);
+        let mut claimed = false;
+        while !claimed && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+            claimed = cache.claim_prefetch(&query(), Instant::now());
+        }
+        assert!(claimed);
+    }
+
+    #[test]
+    fn test_panicking_prefetch_releases_claim() {
+        let cache = Arc::new(DnsCache::new(10));
+        insert_expiring(&cache);
+        for _ in 0..3 {
+            cache.get(&query(), Instant::now());
+        }
+        assert!(cache.claim_prefetch(&query(), Instant::now()));
+
+        let claim = PrefetchClaim {
+            query: query(),
+            cache: cache.clone(),
+        };
+        let pool = WorkerPool::new(1, 1);
+        pool.execute(move || {
+            let _claim = claim;
+            panic!("upstream went away");
+        });
+        let deadline = Instant::now() + Duration::from_secs(2);
+        let mut claimed = false;
+        while !claimed && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+            claimed = cache.claim_prefetch(&query(), Instant::now());
+        }
+        assert!(claimed);
+    }
+
+    /// Stored 95 seconds ago with a TTL of 100, so a popular entry is due for a prefetch
+    fn insert_expiring(cache: &DnsCache) {
+        let stored = Instant::now().checked_sub(Duration::from_secs(95)).unwrap();
+        let answer = Packet::builder()
+            .header(Header {
+                qr: QueryResponse::Reply,
+                ..Default::default()
+            })
+            .questions(query().questions)
+            .answer(Answer {
+                label: Label("codecrafters.io".to_string()),
+                typez: RecordType::A,
+                class: RecordClass::IN,
+                ttl: 100,
+                rdata: RData::A(Ipv4Addr::new(10, 0, 0, 1)),
+            })
+            .build();
+        cache.insert(&query(), &answer, stored);
+    }
+
+    #[test]
+    fn test_swappable_handler() {
+        let handler = SwappableHandler::new(Arc::new(MockHandler));
+        assert_eq!(handler.handle(query(), &client()).header.ancount, 1);
+        handler.swap(Arc::new(ForwardHandler::new(
+            vec!["not an address".to_string()],
+            Duration::from_secs(1),
+        )));
+        assert_eq!(
+            handler.handle(query(), &client()).header.rcode,
+            RCode::ServFail
+        );
+    }
+}
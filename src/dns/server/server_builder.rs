@@ -0,0 +1,85 @@
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use super::{
+    middleware::MiddlewareChain,
+    tcp_listener::TCP_IDLE_TIMEOUT,
+    worker_pool::{DEFAULT_QUEUE_SIZE, DEFAULT_WORKERS},
+    DnsServer, Middleware, MockHandler, RequestHandler,
+};
+
+/// Listener addresses may be IPv4 or IPv6, port 0 picks a free port which is reported by
+/// `ServerHandle::local_addrs` once started. On Linux `[::]` usually takes IPv4 as well, so it
+/// can't be combined with `0.0.0.0` on the same port. Queries are answered by [`MockHandler`]
+/// unless another handler is set, middlewares wrap it in the order they are added, the first
+/// one sees the query first
+pub struct DnsServerBuilder {
+    listeners: Vec<SocketAddr>,
+    handler: Arc<dyn RequestHandler>,
+    middlewares: Vec<Arc<dyn Middleware>>,
+    workers: usize,
+    queue_size: usize,
+    tcp_idle_timeout: Duration,
+}
+
+impl Default for DnsServerBuilder {
+    fn default() -> Self {
+        Self {
+            listeners: vec![],
+            handler: Arc::new(MockHandler),
+            middlewares: vec![],
+            workers: DEFAULT_WORKERS,
+            queue_size: DEFAULT_QUEUE_SIZE,
+            tcp_idle_timeout: TCP_IDLE_TIMEOUT,
+        }
+    }
+}
+
+impl DnsServerBuilder {
+    pub fn listen(mut self, addr: SocketAddr) -> Self {
+        self.listeners.push(addr);
+        self
+    }
+    pub fn listeners(mut self, listeners: Vec<SocketAddr>) -> Self {
+        self.listeners = listeners;
+        self
+    }
+    pub fn handler(mut self, handler: impl RequestHandler + 'static) -> Self {
+        self.handler = Arc::new(handler);
+        self
+    }
+    pub fn middleware(mut self, middleware: impl Middleware + 'static) -> Self {
+        self.middlewares.push(Arc::new(middleware));
+        self
+    }
+    pub fn workers(mut self, workers: usize) -> Self {
+        self.workers = workers;
+        self
+    }
+    pub fn queue_size(mut self, queue_size: usize) -> Self {
+        self.queue_size = queue_size;
+        self
+    }
+    pub fn tcp_idle_timeout(mut self, tcp_idle_timeout: Duration) -> Self {
+        self.tcp_idle_timeout = tcp_idle_timeout;
+        self
+    }
+    pub fn build(self) -> DnsServer {
+        let handler = match self.middlewares.is_empty() {
+            true => self.handler,
+            false => Arc::new(MiddlewareChain::new(self.middlewares, self.handler)),
+        };
+        DnsServer {
+            listeners: self.listeners,
+            handler,
+            workers: self.workers,
+            queue_size: self.queue_size,
+            tcp_idle_timeout: self.tcp_idle_timeout,
+        }
+    }
+}
+
+impl DnsServer {
+    pub fn builder() -> DnsServerBuilder {
+        DnsServerBuilder::default()
+    }
+}
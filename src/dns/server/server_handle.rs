@@ -0,0 +1,343 @@
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use signal_hook::{
+    consts::{SIGHUP, SIGINT, SIGTERM},
+    iterator::Signals,
+};
+use tracing::{error, info, warn};
+
+use crate::fdbg;
+
+use super::{
+    tcp_listener::TcpConnections, worker_pool::WorkerPool, RequestHandler, SwappableHandler,
+};
+
+/// How long in flight queries get to finish by default once a shutdown signal is received
+pub const SHUTDOWN_DEADLINE: Duration = Duration::from_secs(5);
+
+/// A running server, returned by `DnsServer::start`
+pub struct ServerHandle {
+    pub(super) listeners: Vec<RunningListener>,
+    pub(super) stop: Arc<AtomicBool>,
+    pub(super) pool: Arc<WorkerPool>,
+    pub(super) handler: Arc<SwappableHandler>,
+}
+
+/// UDP and TCP threads of one listener address
+pub(super) struct RunningListener {
+    pub(super) addr: SocketAddr,
+    pub(super) udp: JoinHandle<()>,
+    pub(super) tcp: JoinHandle<()>,
+    pub(super) tcp_connections: Arc<TcpConnections>,
+}
+
+impl ServerHandle {
+    /// Address of the first listener, with the port that was picked when asked for port 0
+    pub fn local_addr(&self) -> SocketAddr {
+        self.listeners[0].addr
+    }
+
+    /// Every listener address in the order they were configured
+    pub fn local_addrs(&self) -> Vec<SocketAddr> {
+        self.listeners
+            .iter()
+            .map(|listener| listener.addr)
+            .collect()
+    }
+
+    /// Answers the next queries with `handler` while the listeners keep running, queries
+    /// already being answered are not affected
+    pub fn set_handler(&self, handler: impl RequestHandler + 'static) {
+        self.handler.swap(Arc::new(handler));
+    }
+
+    /// Stops every listener, then waits for the queries already received until the deadline.
+    /// Returns false when some were still in flight at the deadline
+    pub fn shutdown(self, deadline: Duration) -> bool {
+        let deadline = Instant::now() + deadline;
+        self.stop.store(true, Ordering::SeqCst);
+
+        let mut tcp_connections = vec![];
+        for listener in self.listeners {
+            if listener.udp.join().is_err() {
+                error!("UDP listener on {} panicked", listener.addr);
+            }
+            if listener.tcp.join().is_err() {
+                error!("TCP listener on {} panicked", listener.addr);
+            }
+            listener.tcp_connections.close_all();
+            tcp_connections.push(listener.tcp_connections);
+        }
+        let tcp_drained = tcp_connections
+            .iter()
+            .all(|connections| connections.wait_closed(deadline));
+        // every listener is joined and every connection closed, nothing else holds the pool
+        let pool_drained = match Arc::try_unwrap(self.pool) {
+            Ok(pool) => pool.shutdown(deadline),
+            Err(_) => false,
+        };
+        info!("DNS server is stopped");
+        tcp_drained && pool_drained
+    }
+
+    /// Blocks until SIGTERM or SIGINT and then shuts down, `reload` is called on SIGHUP and can
+    /// swap the handler with [`ServerHandle::set_handler`]
+    pub fn wait_for_signals(
+        self,
+        deadline: Duration,
+        mut reload: impl FnMut(&ServerHandle),
+    ) -> anyhow::Result<()> {
+        let mut signals = Signals::new([SIGTERM, SIGINT, SIGHUP])
+            .context(fdbg!("Unable to listen for signals"))?;
+        for signal in signals.forever() {
+            if signal == SIGHUP {
+                info!("Received SIGHUP, reloading configuration");
+                reload(&self);
+                continue;
+            }
+            info!("Received signal {signal}, shutting down");
+            break;
+        }
+        if !self.shutdown(deadline) {
+            warn!("Some queries were still in flight after {deadline:?}");
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{Ipv4Addr, SocketAddr, TcpStream, UdpSocket},
+        sync::Arc,
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use crate::{
+        common::{dns_reader::DnsReader, AsBytes, Parse},
+        dns::{
+            answer::{Answer, RData},
+            cache::DnsCache,
+            header::{Header, OpCode, QueryResponse, RCode},
+            label::Label,
+            packet::Packet,
+            question::Question,
+            server::{
+                worker_pool::WorkerPool, ClientInfo, DnsServer, ForwardHandler, RequestHandler,
+                ServerHandle,
+            },
+            tcp, RecordClass, RecordType,
+        },
+    };
+
+    fn status_query() -> Vec<u8> {
+        Packet::builder()
+            .header(Header {
+                id: 42,
+                opcode: OpCode::Status,
+                ..Default::default()
+            })
+            .build()
+            .as_bytes()
+    }
+
+    fn a_query() -> Packet {
+        Packet::builder()
+            .header(Header {
+                id: 7,
+                ..Default::default()
+            })
+            .question(Question {
+                name: Label("codecrafters.io".to_string()),
+                typez: RecordType::A,
+                class: RecordClass::IN,
+            })
+            .build()
+    }
+
+    fn a_reply(query: &Packet, ttl: u32) -> Packet {
+        Packet::builder()
+            .header(Header {
+                qr: QueryResponse::Reply,
+                ..query.header.clone()
+            })
+            .answer(Answer {
+                label: query.questions[0].name.clone(),
+                typez: RecordType::A,
+                class: RecordClass::IN,
+                ttl,
+                rdata: RData::A(Ipv4Addr::new(10, 0, 0, 1)),
+            })
+            .questions(query.questions.clone())
+            .build()
+    }
+
+    fn start(listeners: &[&str]) -> ServerHandle {
+        DnsServer::builder()
+            .listeners(listeners.iter().map(|addr| addr.parse().unwrap()).collect())
+            .workers(2)
+            .build()
+            .start()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_start_and_shutdown() {
+        for _ in 0..3 {
+            let server = start(&["127.0.0.1:0"]);
+            let addr = server.local_addr();
+            assert_ne!(addr.port(), 0);
+
+            let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+            client
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            client.send_to(&status_query(), addr).unwrap();
+            let mut buf = [0; 512];
+            let size = client.recv(&mut buf).unwrap();
+            let response = Packet::parse(&mut DnsReader::new(&buf[..size])).unwrap();
+            assert_eq!(response.header.rcode, RCode::NotImp);
+
+            let mut stream = TcpStream::connect(addr).unwrap();
+            tcp::write_message(&mut stream, &status_query()).unwrap();
+            let buf = tcp::read_message(&mut stream).unwrap();
+            let response = Packet::parse(&mut DnsReader::new(&buf)).unwrap();
+            assert_eq!(response.header.id, 42);
+
+            // the open TCP connection is closed as part of the shutdown
+            assert!(server.shutdown(Duration::from_secs(5)));
+            assert!(tcp::read_message(&mut stream).is_err());
+            assert!(TcpStream::connect(addr).is_err());
+        }
+    }
+
+    #[test]
+    fn test_multiple_listeners() {
+        let server = start(&["127.0.0.1:0", "[::1]:0"]);
+        let addrs = server.local_addrs();
+        assert_eq!(addrs.len(), 2);
+        assert!(addrs[0].is_ipv4());
+        assert!(addrs[1].is_ipv6());
+        for addr in &addrs {
+            let client = UdpSocket::bind(SocketAddr::new(addr.ip(), 0)).unwrap();
+            client
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            client.send_to(&status_query(), addr).unwrap();
+            let mut buf = [0; 512];
+            let size = client.recv(&mut buf).unwrap();
+            let response = Packet::parse(&mut DnsReader::new(&buf[..size])).unwrap();
+            assert_eq!(response.header.id, 42);
+        }
+        assert!(server.shutdown(Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn test_nothing_started_when_bind_fails() {
+        let taken = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let taken = taken.local_addr().unwrap().to_string();
+        assert!(DnsServer::builder()
+            .listen("127.0.0.1:0".parse().unwrap())
+            .listen(taken.parse().unwrap())
+            .build()
+            .start()
+            .is_err());
+        assert!(DnsServer::builder().build().start().is_err());
+    }
+
+    struct Refuse;
+
+    impl RequestHandler for Refuse {
+        fn handle(&self, packet: Packet, _client: &ClientInfo) -> Packet {
+            DnsServer::error_response(packet, RCode::Refused)
+        }
+    }
+
+    #[test]
+    fn test_set_handler_while_running() {
+        let server = start(&["127.0.0.1:0"]);
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let query = a_query().as_bytes();
+        let mut ask = || {
+            client.send_to(&query, server.local_addr()).unwrap();
+            let mut buf = [0; 512];
+            let size = client.recv(&mut buf).unwrap();
+            Packet::parse(&mut DnsReader::new(&buf[..size])).unwrap()
+        };
+        assert_eq!(ask().header.ancount, 1);
+        server.set_handler(Refuse);
+        assert_eq!(ask().header.rcode, RCode::Refused);
+        assert!(server.shutdown(Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn test_set_handler_during_slow_prefetch() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let upstream_addr = upstream.local_addr().unwrap().to_string();
+        // answers the prefetch after a while, the reload happens in the meantime
+        let upstream = thread::spawn(move || {
+            let mut buf = [0; 512];
+            let (size, source) = upstream.recv_from(&mut buf).unwrap();
+            let query = Packet::parse(&mut DnsReader::new(&buf[..size])).unwrap();
+            thread::sleep(Duration::from_secs(1));
+            upstream
+                .send_to(&a_reply(&query, 300).as_bytes(), source)
+                .unwrap();
+        });
+
+        // popular and about to expire, the next hit starts a prefetch
+        let cache = Arc::new(DnsCache::new(10));
+        let stored = Instant::now().checked_sub(Duration::from_secs(95)).unwrap();
+        cache.insert(&a_query(), &a_reply(&a_query(), 100), stored);
+        for _ in 0..2 {
+            cache.get(&a_query(), Instant::now());
+        }
+        let prefetch = Arc::new(WorkerPool::new(1, 1));
+        let forward = || {
+            ForwardHandler::new(vec![upstream_addr.clone()], Duration::from_secs(5))
+                .cache(cache.clone(), prefetch.clone())
+        };
+        let server = DnsServer::builder()
+            .listen("127.0.0.1:0".parse().unwrap())
+            .handler(forward())
+            .build()
+            .start()
+            .unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let query = a_query().as_bytes();
+        let mut ask = || {
+            client.send_to(&query, server.local_addr()).unwrap();
+            let mut buf = [0; 512];
+            let size = client.recv(&mut buf).unwrap();
+            Packet::parse(&mut DnsReader::new(&buf[..size])).unwrap()
+        };
+        assert_eq!(ask().answers[0].ttl, 5);
+
+        // the old handler goes away while its prefetch still waits for the upstream
+        let reload = Instant::now();
+        server.set_handler(forward());
+        assert!(reload.elapsed() < Duration::from_millis(500));
+        let asked = Instant::now();
+        assert_eq!(ask().answers.len(), 1);
+        assert!(asked.elapsed() < Duration::from_millis(500));
+
+        upstream.join().unwrap();
+        assert!(server.shutdown(Duration::from_secs(5)));
+    }
+}
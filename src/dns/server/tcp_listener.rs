@@ -0,0 +1,498 @@
+use std::{
+    collections::HashMap,
+    io::{self, ErrorKind, Read},
+    net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use tracing::{debug, error, warn};
+
+use crate::dns::tcp;
+
+use super::{worker_pool::WorkerPool, ClientInfo, DnsServer, RequestHandler, Transport};
+
+/// How long a connection may take to send its next query in full before we close it
+/// https://www.rfc-editor.org/rfc/rfc7766#section-6.2.3
+pub const TCP_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Connections over this number are closed right after they are accepted
+pub const MAX_TCP_CONNECTIONS: usize = 128;
+/// How often the listener stops waiting for a connection to check if it should shut down
+const TCP_POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// Queries of one connection answered at the same time, we stop reading until one is done
+pub const MAX_TCP_IN_FLIGHT: usize = 16;
+
+/// DNS over TCP, every connection gets its own thread reading queries. They are answered by
+/// the worker pool shared with UDP, up to [`MAX_TCP_IN_FLIGHT`] at once per connection, so
+/// pipelined queries are answered in whatever order they finish
+/// https://www.rfc-editor.org/rfc/rfc7766
+pub struct DnsTcpListener {
+    listener: TcpListener,
+    connections: Arc<TcpConnections>,
+    idle_timeout: Duration,
+}
+
+impl DnsTcpListener {
+    pub fn bind(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        Ok(Self {
+            listener,
+            connections: Arc::default(),
+            idle_timeout: TCP_IDLE_TIMEOUT,
+        })
+    }
+
+    pub fn idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.idle_timeout = idle_timeout;
+        self
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    pub fn connections(&self) -> Arc<TcpConnections> {
+        self.connections.clone()
+    }
+
+    /// Accepts connections until `stop` is set, checked every [`TCP_POLL_INTERVAL`]
+    pub fn start(
+        self,
+        handler: Arc<dyn RequestHandler>,
+        pool: Arc<WorkerPool>,
+        stop: Arc<AtomicBool>,
+    ) {
+        while !stop.load(Ordering::SeqCst) {
+            let stream = match self.listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                    thread::sleep(TCP_POLL_INTERVAL);
+                    continue;
+                }
+                Err(e) => {
+                    error!("Error accepting TCP connection, {e}");
+                    continue;
+                }
+            };
+            // the connection would inherit nonblocking mode on some platforms
+            if let Err(e) = stream.set_nonblocking(false) {
+                error!("Unable to set TCP connection to blocking, {e}");
+                continue;
+            }
+            let guard = match self.connections.register(&stream) {
+                Ok(guard) => guard,
+                Err(e) => {
+                    error!("Unable to register TCP connection, {e}");
+                    continue;
+                }
+            };
+            if guard.count > MAX_TCP_CONNECTIONS {
+                warn!("Too many TCP connections, closing {:?}", stream.peer_addr());
+                continue;
+            }
+            let handler = handler.clone();
+            let pool = pool.clone();
+            let idle_timeout = self.idle_timeout;
+            thread::spawn(move || {
+                // the pool goes first, once every connection is closed nothing else holds it
+                if let Err(e) = handle_connection(stream, handler, pool, idle_timeout) {
+                    debug!("TCP connection closed, {e}");
+                }
+                drop(guard);
+            });
+        }
+        debug!("TCP listener is stopped");
+    }
+}
+
+/// Open connections, kept so they can be closed on shutdown
+#[derive(Default)]
+pub struct TcpConnections {
+    open: Mutex<HashMap<u64, TcpStream>>,
+    next_id: AtomicU64,
+}
+
+impl TcpConnections {
+    fn register(self: &Arc<Self>, stream: &TcpStream) -> io::Result<ConnectionGuard> {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let mut open = self.open.lock().expect("TCP connections lock is poisoned");
+        open.insert(id, stream.try_clone()?);
+        Ok(ConnectionGuard {
+            connections: self.clone(),
+            id,
+            count: open.len(),
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.open
+            .lock()
+            .expect("TCP connections lock is poisoned")
+            .len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Stop reading queries, the ones already read are still answered
+    pub fn close_all(&self) {
+        let open = self.open.lock().expect("TCP connections lock is poisoned");
+        for stream in open.values() {
+            // the connection may already be closed by the client
+            let _ = stream.shutdown(Shutdown::Read);
+        }
+    }
+
+    /// Wait for every connection to finish until the deadline, false if some are still open
+    pub fn wait_closed(&self, deadline: Instant) -> bool {
+        while !self.is_empty() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        self.is_empty()
+    }
+}
+
+fn handle_connection(
+    stream: TcpStream,
+    handler: Arc<dyn RequestHandler>,
+    pool: Arc<WorkerPool>,
+    idle_timeout: Duration,
+) -> io::Result<()> {
+    let source = stream.peer_addr()?;
+    stream.set_write_timeout(Some(idle_timeout))?;
+    let reader = stream.try_clone()?;
+    let writer = Arc::new(Mutex::new(stream));
+    let in_flight = Arc::new(InFlight::default());
+    let result = loop {
+        let slot = in_flight.acquire();
+        let mut reader = DeadlineReader {
+            stream: &reader,
+            deadline: Instant::now() + idle_timeout,
+        };
+        let query = match tcp::read_message(&mut reader) {
+            Ok(query) => query,
+            Err(e) if is_closed(&e) => break Ok(()),
+            Err(e) => break Err(e),
+        };
+        let (handler, writer) = (handler.clone(), writer.clone());
+        // waits for room in the queue, the client waits a little longer instead of losing
+        // the query
+        pool.execute(move || {
+            let _slot = slot;
+            respond(&*handler, &writer, &query, source);
+        });
+    };
+    // in flight queries are still answered when the client stops sending
+    in_flight.wait_idle();
+    result
+}
+
+/// Number of queries being answered on a connection
+#[derive(Default)]
+struct InFlight {
+    count: Mutex<usize>,
+    done: Condvar,
+}
+
+impl InFlight {
+    /// Waits until there is room for one more query, the slot is given back when dropped
+    fn acquire(self: &Arc<Self>) -> InFlightSlot {
+        let count = self.count.lock().expect("TCP in flight lock is poisoned");
+        let mut count = self
+            .done
+            .wait_while(count, |count| *count >= MAX_TCP_IN_FLIGHT)
+            .expect("TCP in flight lock is poisoned");
+        *count += 1;
+        InFlightSlot(self.clone())
+    }
+
+    fn wait_idle(&self) {
+        let count = self.count.lock().expect("TCP in flight lock is poisoned");
+        let _count = self
+            .done
+            .wait_while(count, |count| *count > 0)
+            .expect("TCP in flight lock is poisoned");
+    }
+}
+
+/// Room for one query in [`InFlight`], also given back when answering it panics
+struct InFlightSlot(Arc<InFlight>);
+
+impl Drop for InFlightSlot {
+    fn drop(&mut self) {
+        *self.0.count.lock().expect("TCP in flight lock is poisoned") -= 1;
+        self.0.done.notify_all();
+    }
+}
+
+/// Reads from the stream until the deadline, a client sending a byte now and then can't keep
+/// the connection open with a query that never completes
+struct DeadlineReader<'a> {
+    stream: &'a TcpStream,
+    deadline: Instant,
+}
+
+impl Read for DeadlineReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let left = self.deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Err(ErrorKind::TimedOut.into());
+        }
+        self.stream.set_read_timeout(Some(left))?;
+        self.stream.read(buf)
+    }
+}
+
+fn respond(
+    handler: &dyn RequestHandler,
+    writer: &Mutex<TcpStream>,
+    query: &[u8],
+    source: SocketAddr,
+) {
+    let client = ClientInfo {
+        source,
+        transport: Transport::Tcp,
+    };
+    let Some(response) = DnsServer::handle_request(handler, query, client) else {
+        return;
+    };
+    let mut stream = writer.lock().expect("TCP writer lock is poisoned");
+    if let Err(e) = tcp::write_message(&mut *stream, &response) {
+        error!("Failed to send TCP response to {source}, {e}");
+    }
+}
+
+/// Client closing the connection or going idle is how connections normally end
+fn is_closed(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        ErrorKind::UnexpectedEof | ErrorKind::WouldBlock | ErrorKind::TimedOut
+    )
+}
+
+/// Keeps the connection registered, it is removed when the guard is dropped
+struct ConnectionGuard {
+    connections: Arc<TcpConnections>,
+    id: u64,
+    count: usize,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.connections
+            .open
+            .lock()
+            .expect("TCP connections lock is poisoned")
+            .remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashSet,
+        io::Write,
+        net::TcpStream,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use crate::{
+        common::{dns_reader::DnsReader, AsBytes, Parse},
+        dns::{
+            header::{Header, OpCode, RCode},
+            label::Label,
+            packet::Packet,
+            question::Question,
+            tcp, RecordClass, RecordType,
+        },
+    };
+
+    use super::{
+        super::{worker_pool::WorkerPool, ClientInfo, MockHandler, RequestHandler},
+        DnsTcpListener, MAX_TCP_IN_FLIGHT,
+    };
+
+    /// More workers than a connection may use, so the in flight cap is what limits it
+    fn pool() -> Arc<WorkerPool> {
+        Arc::new(WorkerPool::new(MAX_TCP_IN_FLIGHT * 2, 64))
+    }
+
+    fn query(id: u16, opcode: OpCode) -> Packet {
+        Packet::builder()
+            .header(Header {
+                id,
+                opcode,
+                ..Default::default()
+            })
+            .question(Question {
+                name: Label("codecrafters.io".to_string()),
+                typez: RecordType::A,
+                class: RecordClass::IN,
+            })
+            .build()
+    }
+
+    fn tcp_message(message: &[u8]) -> Vec<u8> {
+        let mut buf = vec![];
+        tcp::write_message(&mut buf, message).unwrap();
+        buf
+    }
+
+    /// Slow handler keeping track of how many queries it answers at the same time
+    #[derive(Default)]
+    struct SlowHandler {
+        running: AtomicUsize,
+        max_running: AtomicUsize,
+    }
+
+    impl RequestHandler for SlowHandler {
+        fn handle(&self, packet: Packet, client: &ClientInfo) -> Packet {
+            let running = self.running.fetch_add(1, Ordering::SeqCst) + 1;
+            self.max_running.fetch_max(running, Ordering::SeqCst);
+            thread::sleep(Duration::from_millis(20));
+            self.running.fetch_sub(1, Ordering::SeqCst);
+            MockHandler.handle(packet, client)
+        }
+    }
+
+    #[test]
+    fn test_pipelined_queries() {
+        let listener = DnsTcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || listener.start(Arc::new(MockHandler), pool(), Arc::default()));
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        for id in 1..=3 {
+            let query = Packet::builder()
+                .header(Header {
+                    id,
+                    opcode: OpCode::Status,
+                    ..Default::default()
+                })
+                .question(Question {
+                    name: Label("codecrafters.io".to_string()),
+                    typez: RecordType::A,
+                    class: RecordClass::IN,
+                })
+                .build();
+            tcp::write_message(&mut stream, &query.as_bytes()).unwrap();
+        }
+        let mut ids = HashSet::new();
+        for _ in 1..=3 {
+            let buf = tcp::read_message(&mut stream).unwrap();
+            let response = Packet::parse(&mut DnsReader::new(&buf)).unwrap();
+            assert_eq!(response.header.rcode, RCode::NotImp);
+            ids.insert(response.header.id);
+        }
+        assert_eq!(ids, HashSet::from([1, 2, 3]));
+    }
+
+    #[test]
+    fn test_close_all_connections() {
+        let listener = DnsTcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let connections = listener.connections();
+        let stop = Arc::default();
+        let listener = {
+            let stop = Arc::clone(&stop);
+            thread::spawn(move || listener.start(Arc::new(MockHandler), pool(), stop))
+        };
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while connections.is_empty() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(connections.len(), 1);
+        connections.close_all();
+        assert!(connections.wait_closed(Instant::now() + Duration::from_secs(5)));
+        // the server closed its side, so the client sees the end of the stream
+        assert!(tcp::read_message(&mut stream).is_err());
+
+        stop.store(true, Ordering::SeqCst);
+        listener.join().unwrap();
+    }
+
+    #[test]
+    fn test_in_flight_queries_are_capped() {
+        let listener = DnsTcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handler = Arc::new(SlowHandler::default());
+        {
+            let handler = handler.clone();
+            thread::spawn(move || listener.start(handler, pool(), Arc::default()));
+        }
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let count = MAX_TCP_IN_FLIGHT as u16 * 3;
+        for id in 1..=count {
+            tcp::write_message(&mut stream, &query(id, OpCode::Query).as_bytes()).unwrap();
+        }
+        let ids = (1..=count)
+            .map(|_| {
+                let buf = tcp::read_message(&mut stream).unwrap();
+                Packet::parse(&mut DnsReader::new(&buf)).unwrap().header.id
+            })
+            .collect::<HashSet<_>>();
+        assert_eq!(ids.len(), count as usize);
+        assert!(handler.max_running.load(Ordering::SeqCst) <= MAX_TCP_IN_FLIGHT);
+    }
+
+    struct PanicHandler;
+
+    impl RequestHandler for PanicHandler {
+        fn handle(&self, packet: Packet, client: &ClientInfo) -> Packet {
+            assert!(packet.header.id >= 100, "query {} panics", packet.header.id);
+            MockHandler.handle(packet, client)
+        }
+    }
+
+    #[test]
+    fn test_panicking_queries_give_back_their_slot() {
+        let listener = DnsTcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || listener.start(Arc::new(PanicHandler), pool(), Arc::default()));
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        for id in 1..=MAX_TCP_IN_FLIGHT as u16 {
+            tcp::write_message(&mut stream, &query(id, OpCode::Query).as_bytes()).unwrap();
+        }
+        tcp::write_message(&mut stream, &query(100, OpCode::Query).as_bytes()).unwrap();
+        let buf = tcp::read_message(&mut stream).unwrap();
+        let response = Packet::parse(&mut DnsReader::new(&buf)).unwrap();
+        assert_eq!(response.header.id, 100);
+    }
+
+    #[test]
+    fn test_slow_query_hits_deadline() {
+        let listener = DnsTcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .idle_timeout(Duration::from_millis(200));
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || listener.start(Arc::new(MockHandler), pool(), Arc::default()));
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let message = tcp_message(&query(1, OpCode::Status).as_bytes());
+        // a byte at a time, each well within the timeout but the query as a whole is not, the
+        // server closing the connection makes the writes fail
+        let closed = message[..message.len() - 1].iter().any(|byte| {
+            thread::sleep(Duration::from_millis(50));
+            stream.write_all(&[*byte]).is_err()
+        });
+        assert!(closed);
+    }
+}
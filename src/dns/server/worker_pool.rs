@@ -0,0 +1,217 @@
+use std::{
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        mpsc::{self, Receiver, SyncSender, TrySendError},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use tracing::{debug, error, warn};
+
+/// Threads answering queries, enough to keep many slow upstream lookups in flight
+pub const DEFAULT_WORKERS: usize = 16;
+/// Queries waiting for a free worker. UDP queries above that are dropped, TCP connections
+/// stop reading until there is room
+pub const DEFAULT_QUEUE_SIZE: usize = 1024;
+
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// Fixed number of threads taking jobs from a bounded queue
+pub struct WorkerPool {
+    sender: Option<SyncSender<Job>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl WorkerPool {
+    pub fn new(workers: usize, queue_size: usize) -> Self {
+        let (sender, receiver) = mpsc::sync_channel::<Job>(queue_size);
+        let receiver = Arc::new(Mutex::new(receiver));
+        let workers = (0..workers.max(1))
+            .map(|i| {
+                let receiver = receiver.clone();
+                thread::Builder::new()
+                    .name(format!("dns-worker-{i}"))
+                    .spawn(move || Self::work(receiver))
+                    .expect("Unable to spawn worker thread")
+            })
+            .collect();
+        Self {
+            sender: Some(sender),
+            workers,
+        }
+    }
+
+    fn work(receiver: Arc<Mutex<Receiver<Job>>>) {
+        loop {
+            // lock is released as soon as a job is taken, so other workers can take the next one
+            let job = receiver
+                .lock()
+                .expect("Worker queue lock is poisoned")
+                .recv();
+            match job {
+                // a panicking job must not take the worker with it
+                Ok(job) => {
+                    if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+                        error!("Worker job panicked");
+                    }
+                }
+                // pool is shutting down and the queue is drained
+                Err(_) => return,
+            }
+        }
+    }
+
+    /// Queue the job, false when the queue is full and the job was dropped
+    pub fn try_execute<F>(&self, job: F) -> bool
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        match self.sender().try_send(Box::new(job)) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => false,
+            Err(TrySendError::Disconnected(_)) => {
+                error!("Worker pool is shut down, dropping job");
+                false
+            }
+        }
+    }
+
+    /// Queue the job, waiting for room in the queue if needed
+    pub fn execute<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        if self.sender().send(Box::new(job)).is_err() {
+            error!("Worker pool is shut down, dropping job");
+        }
+    }
+
+    /// Stop taking new jobs and wait for the queued ones until the deadline. Returns false when
+    /// some workers were still busy, they are left running in the background
+    pub fn shutdown(mut self, deadline: Instant) -> bool {
+        drop(self.sender.take());
+        let mut workers = std::mem::take(&mut self.workers);
+        while !workers.is_empty() && Instant::now() < deadline {
+            workers.retain(|worker| !worker.is_finished());
+            thread::sleep(SHUTDOWN_POLL_INTERVAL);
+        }
+        workers.retain(|worker| !worker.is_finished());
+        if !workers.is_empty() {
+            warn!(
+                "{} workers are still busy after the deadline",
+                workers.len()
+            );
+        }
+        workers.is_empty()
+    }
+
+    fn sender(&self) -> &SyncSender<Job> {
+        self.sender.as_ref().expect("Worker pool sender is gone")
+    }
+}
+
+/// Jobs already queued are still run, then the workers are joined
+impl Drop for WorkerPool {
+    fn drop(&mut self) {
+        drop(self.sender.take());
+        for worker in self.workers.drain(..) {
+            if worker.join().is_err() {
+                error!("Worker thread panicked");
+            }
+        }
+        debug!("Worker pool is shut down");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            mpsc, Arc,
+        },
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use super::WorkerPool;
+
+    #[test]
+    fn test_runs_jobs_concurrently() {
+        let pool = WorkerPool::new(4, 16);
+        let (sender, receiver) = mpsc::channel();
+        // every job waits for all 4 to be running, a single thread would deadlock
+        let barrier = Arc::new(std::sync::Barrier::new(4));
+        for i in 0..4 {
+            let sender = sender.clone();
+            let barrier = barrier.clone();
+            pool.execute(move || {
+                barrier.wait();
+                sender.send(i).unwrap();
+            });
+        }
+        let mut done = (0..4)
+            .map(|_| receiver.recv_timeout(Duration::from_secs(5)).unwrap())
+            .collect::<Vec<_>>();
+        done.sort();
+        assert_eq!(done, vec![0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn test_try_execute_when_full() {
+        let pool = WorkerPool::new(1, 1);
+        let (block_sender, block_receiver) = mpsc::channel::<()>();
+        let (started_sender, started_receiver) = mpsc::channel();
+        pool.execute(move || {
+            started_sender.send(()).unwrap();
+            block_receiver.recv().unwrap();
+        });
+        started_receiver.recv().unwrap();
+        // the only worker is busy, one job fits in the queue
+        assert!(pool.try_execute(|| {}));
+        assert!(!pool.try_execute(|| {}));
+        block_sender.send(()).unwrap();
+    }
+
+    #[test]
+    fn test_drop_drains_queue() {
+        let count = Arc::new(AtomicUsize::new(0));
+        let pool = WorkerPool::new(2, 100);
+        for _ in 0..50 {
+            let count = count.clone();
+            pool.execute(move || {
+                count.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+        drop(pool);
+        assert_eq!(count.load(Ordering::SeqCst), 50);
+    }
+
+    #[test]
+    fn test_shutdown_deadline() {
+        let pool = WorkerPool::new(1, 1);
+        let (sender, receiver) = mpsc::channel::<()>();
+        pool.execute(move || {
+            let _ = receiver.recv();
+        });
+        assert!(!pool.shutdown(Instant::now() + Duration::from_millis(50)));
+        drop(sender);
+
+        let pool = WorkerPool::new(1, 1);
+        pool.execute(|| thread::sleep(Duration::from_millis(20)));
+        assert!(pool.shutdown(Instant::now() + Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn test_worker_survives_panicking_job() {
+        let pool = WorkerPool::new(1, 4);
+        pool.execute(|| panic!("job panics"));
+        let (sender, receiver) = mpsc::channel();
+        pool.execute(move || sender.send(()).unwrap());
+        assert!(receiver.recv_timeout(Duration::from_secs(5)).is_ok());
+    }
+}
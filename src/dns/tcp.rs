@@ -0,0 +1,48 @@
+use std::io::{self, Read, Write};
+
+/// Messages over TCP are prefixed with a two byte length
+/// https://www.rfc-editor.org/rfc/rfc1035#section-4.2.2
+pub fn read_message(stream: &mut impl Read) -> io::Result<Vec<u8>> {
+    let mut len: [u8; 2] = [0; 2];
+    stream.read_exact(&mut len)?;
+    let mut buf = vec![0; u16::from_be_bytes(len) as usize];
+    stream.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+/// Length and message go out in a single write so they are likely to share a segment
+/// https://www.rfc-editor.org/rfc/rfc7766#section-8
+pub fn write_message(stream: &mut impl Write, message: &[u8]) -> io::Result<()> {
+    let len = u16::try_from(message.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "message too long for TCP"))?;
+    let mut buf = Vec::with_capacity(message.len() + 2);
+    buf.extend(len.to_be_bytes());
+    buf.extend(message);
+    stream.write_all(&buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::{read_message, write_message};
+
+    #[test]
+    fn test_framing_round_trip() {
+        let mut buf = vec![];
+        write_message(&mut buf, &[1, 2, 3]).unwrap();
+        write_message(&mut buf, &[]).unwrap();
+        assert_eq!(buf, vec![0, 3, 1, 2, 3, 0, 0]);
+
+        let mut cursor = Cursor::new(buf);
+        assert_eq!(read_message(&mut cursor).unwrap(), vec![1, 2, 3]);
+        assert_eq!(read_message(&mut cursor).unwrap(), Vec::<u8>::new());
+        assert!(read_message(&mut cursor).is_err());
+    }
+
+    #[test]
+    fn test_short_message() {
+        let mut cursor = Cursor::new(vec![0, 5, 1, 2]);
+        assert!(read_message(&mut cursor).is_err());
+    }
+}
@@ -0,0 +1,374 @@
+use std::net::Ipv4Addr;
+
+use super::{
+    answer::{Answer, RData},
+    label::Label,
+    RecordClass, RecordType,
+};
+
+/// A label holds at most 63 bytes
+/// https://www.rfc-editor.org/rfc/rfc1035#section-2.3.4
+const MAX_LABEL_LENGTH: usize = 63;
+/// A name holds at most 255 bytes in wire format
+const MAX_NAME_LENGTH: usize = 255;
+/// Negative TTL of the SOA we make up for zones that don't configure one
+const DEFAULT_NEGATIVE_TTL: u32 = 300;
+
+/// Records we answer for with authority instead of asking upstream, loaded from the config
+#[derive(Debug, Clone)]
+pub struct Zone {
+    pub name: Label,
+    pub records: Vec<Answer>,
+    /// Goes in the authority section of negative answers
+    /// https://www.rfc-editor.org/rfc/rfc2308#section-3
+    pub soa: Answer,
+}
+
+impl Zone {
+    pub fn new(name: &str) -> Result<Self, String> {
+        let name = Label(normalize(name));
+        validate_name(&name.0)?;
+        Ok(Self {
+            soa: default_soa(&name),
+            name,
+            records: vec![],
+        })
+    }
+
+    /// Whether the name is the zone apex or below it
+    pub fn contains(&self, name: &Label) -> bool {
+        let name = normalize(&name.0);
+        self.name.0.is_empty()
+            || name == self.name.0
+            || name.ends_with(&format!(".{}", self.name.0))
+    }
+
+    /// Records of the name matching the type, a CNAME stands in for any other type.
+    /// None when the name does not exist, names with records below them exist even without
+    /// records of their own
+    /// https://www.rfc-editor.org/rfc/rfc8020
+    pub fn lookup(&self, name: &Label, typez: &RecordType) -> Option<Vec<Answer>> {
+        let name = normalize(&name.0);
+        let records = self
+            .records
+            .iter()
+            .chain((name == self.name.0).then_some(&self.soa))
+            .filter(|record| record.label.0 == name)
+            .collect::<Vec<_>>();
+        let below = format!(".{name}");
+        if records.is_empty()
+            && !self
+                .records
+                .iter()
+                .any(|record| record.label.0.ends_with(&below))
+        {
+            return None;
+        }
+        let matching = records
+            .iter()
+            .filter(|record| {
+                record.typez == *typez
+                    || *typez == RecordType::ANY
+                    || record.typez == RecordType::CNAME
+            })
+            .map(|record| (*record).clone())
+            .collect();
+        Some(matching)
+    }
+
+    /// Adds a record from its presentation form. `@` is the zone apex, names ending with a dot
+    /// are absolute and everything else is relative to the zone
+    pub fn add_record(
+        &mut self,
+        name: &str,
+        typez: &str,
+        ttl: u32,
+        data: &str,
+    ) -> Result<(), String> {
+        let label = match name {
+            "@" => self.name.clone(),
+            name if name.ends_with('.') => Label(normalize(name)),
+            name if self.name.0.is_empty() => Label(normalize(name)),
+            name => Label(normalize(&format!("{name}.{}", self.name.0))),
+        };
+        validate_name(&label.0)?;
+        if !self.contains(&label) {
+            return Err(format!("{label} is not in zone {}", self.name));
+        }
+        let (typez, rdata) = parse_rdata(typez, data)?;
+        let record = Answer {
+            label,
+            typez,
+            class: RecordClass::IN,
+            ttl,
+            rdata,
+        };
+        if record.typez != RecordType::SOA {
+            self.records.push(record);
+        } else if record.label == self.name {
+            self.soa = record;
+        } else {
+            return Err(format!("SOA must be at the zone apex {}", self.name));
+        }
+        Ok(())
+    }
+}
+
+/// Names compare case insensitively and without the trailing dot
+fn normalize(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+/// Empty is the root, anything else needs labels of 1 to 63 bytes and a name that fits in 255
+/// bytes on the wire
+fn validate_name(name: &str) -> Result<(), String> {
+    if name.is_empty() {
+        return Ok(());
+    }
+    if let Some(label) = name
+        .split('.')
+        .find(|label| label.is_empty() || label.len() > MAX_LABEL_LENGTH)
+    {
+        return Err(format!(
+            "invalid name '{name}', label '{label}' must be 1 to {MAX_LABEL_LENGTH} bytes"
+        ));
+    }
+    // every label has a length byte, plus the root at the end
+    if name.len() + 2 > MAX_NAME_LENGTH {
+        return Err(format!(
+            "invalid name '{name}', longer than {MAX_NAME_LENGTH} bytes"
+        ));
+    }
+    Ok(())
+}
+
+fn parse_name(data: &str) -> Result<Label, String> {
+    let name = data.trim_end_matches('.');
+    if name.is_empty() || validate_name(name).is_err() {
+        return Err(format!("invalid name '{data}'"));
+    }
+    Ok(Label(name.to_string()))
+}
+
+/// SOA for a zone without one in the config, only there for its negative TTL
+fn default_soa(zone: &Label) -> Answer {
+    let name = |label: &str| match zone.0.as_str() {
+        "" => Label(label.to_string()),
+        zone => Label(format!("{label}.{zone}")),
+    };
+    Answer {
+        label: zone.clone(),
+        typez: RecordType::SOA,
+        class: RecordClass::IN,
+        ttl: DEFAULT_NEGATIVE_TTL,
+        rdata: RData::SOA {
+            mname: name("ns"),
+            rname: name("hostmaster"),
+            serial: 1,
+            refresh: 3600,
+            retry: 600,
+            expire: 86400,
+            minimum: DEFAULT_NEGATIVE_TTL,
+        },
+    }
+}
+
+fn parse_rdata(typez: &str, data: &str) -> Result<(RecordType, RData), String> {
+    let rdata = match typez.to_ascii_uppercase().as_str() {
+        "A" => (
+            RecordType::A,
+            RData::A(
+                data.parse::<Ipv4Addr>()
+                    .map_err(|_| format!("invalid IPv4 address '{data}'"))?,
+            ),
+        ),
+        "NS" => (RecordType::NS, RData::NS(parse_name(data)?)),
+        "CNAME" => (RecordType::CNAME, RData::CNAME(parse_name(data)?)),
+        "PTR" => (RecordType::PTR, RData::PTR(parse_name(data)?)),
+        "MX" => {
+            let (preference, exchange) = data
+                .split_once(' ')
+                .ok_or_else(|| format!("expected '<preference> <exchange>', got '{data}'"))?;
+            let preference = preference
+                .parse()
+                .map_err(|_| format!("invalid MX preference '{preference}'"))?;
+            (
+                RecordType::MX,
+                RData::MX {
+                    preference,
+                    exchange: parse_name(exchange.trim())?,
+                },
+            )
+        }
+        "SOA" => {
+            let fields = data.split_whitespace().collect::<Vec<_>>();
+            let [mname, rname, numbers @ ..] = fields.as_slice() else {
+                return Err(format!(
+                    "expected '<mname> <rname> <serial> <refresh> <retry> <expire> <minimum>', \
+                     got '{data}'"
+                ));
+            };
+            let numbers = numbers
+                .iter()
+                .map(|n| n.parse::<u32>())
+                .collect::<Result<Vec<_>, _>>();
+            let Ok([serial, refresh, retry, expire, minimum]) = numbers.as_deref() else {
+                return Err(format!(
+                    "expected '<mname> <rname> <serial> <refresh> <retry> <expire> <minimum>', \
+                     got '{data}'"
+                ));
+            };
+            (
+                RecordType::SOA,
+                RData::SOA {
+                    mname: parse_name(mname)?,
+                    rname: parse_name(rname)?,
+                    serial: *serial,
+                    refresh: *refresh,
+                    retry: *retry,
+                    expire: *expire,
+                    minimum: *minimum,
+                },
+            )
+        }
+        "TXT" => {
+            // a <character-string> holds at most 255 bytes, longer text is split over several
+            let strings = data.as_bytes().chunks(255).map(|c| c.to_vec()).collect();
+            (RecordType::TXT, RData::TXT(strings))
+        }
+        _ => return Err(format!("unsupported record type '{typez}'")),
+    };
+    Ok(rdata)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use pretty_assertions::assert_eq;
+
+    use crate::dns::{answer::RData, label::Label, RecordType};
+
+    use super::Zone;
+
+    fn zone() -> Zone {
+        let mut zone = Zone::new("Home.Lan.").unwrap();
+        zone.add_record("@", "NS", 3600, "ns.home.lan").unwrap();
+        zone.add_record("nas", "A", 300, "192.168.1.10").unwrap();
+        zone.add_record("files", "cname", 300, "nas.home.lan.")
+            .unwrap();
+        zone.add_record("mail.home.lan.", "MX", 300, "10 nas.home.lan")
+            .unwrap();
+        zone
+    }
+
+    #[test]
+    fn test_contains() {
+        let zone = zone();
+        assert!(zone.contains(&Label("home.lan".to_string())));
+        assert!(zone.contains(&Label("a.b.HOME.lan.".to_string())));
+        assert!(!zone.contains(&Label("myhome.lan".to_string())));
+        assert!(!zone.contains(&Label("lan".to_string())));
+    }
+
+    #[test]
+    fn test_lookup() {
+        let zone = zone();
+        let records = zone
+            .lookup(&Label("NAS.home.lan".to_string()), &RecordType::A)
+            .unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].rdata, RData::A(Ipv4Addr::new(192, 168, 1, 10)));
+        // the name exists, just not with that type
+        assert!(zone
+            .lookup(&Label("nas.home.lan".to_string()), &RecordType::MX)
+            .unwrap()
+            .is_empty());
+        let records = zone
+            .lookup(&Label("files.home.lan".to_string()), &RecordType::A)
+            .unwrap();
+        assert_eq!(records[0].typez, RecordType::CNAME);
+        assert!(zone
+            .lookup(&Label("printer.home.lan".to_string()), &RecordType::A)
+            .is_none());
+    }
+
+    #[test]
+    fn test_empty_non_terminal() {
+        let mut zone = zone();
+        zone.add_record("tv.media", "A", 300, "192.168.1.20")
+            .unwrap();
+        assert!(zone
+            .lookup(&Label("media.home.lan".to_string()), &RecordType::A)
+            .unwrap()
+            .is_empty());
+        assert!(zone
+            .lookup(&Label("edia.home.lan".to_string()), &RecordType::A)
+            .is_none());
+    }
+
+    #[test]
+    fn test_soa() {
+        let mut zone = zone();
+        let apex = Label("home.lan".to_string());
+        assert_eq!(zone.soa.label, apex);
+        let records = zone.lookup(&apex, &RecordType::SOA).unwrap();
+        assert_eq!(records[0].rdata, zone.soa.rdata);
+
+        zone.add_record(
+            "@",
+            "SOA",
+            60,
+            "ns.home.lan admin.home.lan 7 3600 600 86400 30",
+        )
+        .unwrap();
+        assert_eq!(zone.soa.ttl, 60);
+        assert!(matches!(
+            zone.soa.rdata,
+            RData::SOA {
+                serial: 7,
+                minimum: 30,
+                ..
+            }
+        ));
+        assert!(zone
+            .add_record(
+                "nas",
+                "SOA",
+                60,
+                "ns.home.lan admin.home.lan 7 3600 600 86400 30"
+            )
+            .is_err());
+        assert!(zone.add_record("@", "SOA", 60, "ns.home.lan 7").is_err());
+    }
+
+    #[test]
+    fn test_invalid_names() {
+        assert!(Zone::new("home..lan").is_err());
+        assert!(Zone::new(&format!("{}.lan", "a".repeat(64))).is_err());
+        assert!(Zone::new(&vec!["a".repeat(63); 4].join(".")).is_err());
+        assert!(Zone::new(&vec!["a".repeat(63); 3].join(".")).is_ok());
+        assert!(Zone::new(".").is_ok());
+
+        let mut zone = zone();
+        assert_eq!(
+            zone.add_record("a..b", "A", 300, "1.2.3.4").unwrap_err(),
+            "invalid name 'a..b.home.lan', label '' must be 1 to 63 bytes"
+        );
+        assert!(zone
+            .add_record(&"a".repeat(64), "A", 300, "1.2.3.4")
+            .is_err());
+    }
+
+    #[test]
+    fn test_bad_records() {
+        let mut zone = zone();
+        assert!(zone.add_record("nas", "A", 300, "192.168.1").is_err());
+        assert!(zone.add_record("nas", "AAAA", 300, "::1").is_err());
+        assert!(zone.add_record("nas", "MX", 300, "nas.home.lan").is_err());
+        assert!(zone.add_record("nas", "CNAME", 300, "a..b").is_err());
+        assert!(zone
+            .add_record("example.com.", "A", 300, "1.2.3.4")
+            .is_err());
+    }
+}
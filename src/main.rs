@@ -1,15 +1,208 @@
+use std::{process::ExitCode, sync::Arc};
+
+use tracing::{error, info, warn};
+
 use crate::{
-    config::{cli_args::CliArgs, setup_log},
-    dns::server::DnsServer,
+    config::{
+        cli_args::{CliArgs, CliCommand, USAGE},
+        logging::{LogConfig, LogHandle},
+        settings::Mode,
+        setup_log, Config,
+    },
+    dns::{
+        cache::DnsCache,
+        server::{
+            middleware::{
+                AclMiddleware, BlockMiddleware, LogMiddleware, MiddlewareChain,
+                RateLimitMiddleware, ZoneMiddleware,
+            },
+            request_handler::{PREFETCH_QUEUE_SIZE, PREFETCH_WORKERS},
+            worker_pool::WorkerPool,
+            DnsServer, ForwardHandler, Middleware, MockHandler, RequestHandler, ServerHandle,
+        },
+    },
 };
 
 mod common;
 mod config;
 mod dns;
 
-fn main() {
-    setup_log().expect("Failed to setup log");
-    CliArgs::init();
+/// Exits with 2 for invalid arguments or config and 1 when the server fails while running
+fn main() -> ExitCode {
+    let args = match CliArgs::parse(std::env::args().skip(1)) {
+        Ok(CliCommand::Run(args)) => args,
+        Ok(CliCommand::Help) => {
+            println!("{USAGE}");
+            return ExitCode::SUCCESS;
+        }
+        Ok(CliCommand::Version) => {
+            println!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+            return ExitCode::SUCCESS;
+        }
+        Err(e) => {
+            eprintln!("error: {e}\n\n{USAGE}");
+            return ExitCode::from(2);
+        }
+    };
+    let config = match Config::load(&args) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("error: {e:#}");
+            return ExitCode::from(2);
+        }
+    };
+    let log = match setup_log(&config.log) {
+        Ok(log) => log,
+        Err(e) => {
+            eprintln!("error: {e:#}");
+            return ExitCode::from(2);
+        }
+    };
+    info!("Starting with {config:?}");
+    // kept across reloads, only a restart starts with an empty cache
+    let cache = (config.cache_size > 0)
+        .then(|| Arc::new(DnsCache::new(config.cache_size).stale_window(config.stale_window)));
+    // shared by every handler, dropping an old handler never waits for its refreshes
+    let prefetch = Arc::new(WorkerPool::new(PREFETCH_WORKERS, PREFETCH_QUEUE_SIZE));
+    if let (Some(cache), Some(path)) = (&cache, &config.cache_file) {
+        // nothing saved yet on the first run
+        if path.exists() {
+            match cache.load(path) {
+                Ok(count) => info!("Loaded {count} cached responses from {}", path.display()),
+                Err(e) => warn!("Starting with an empty cache, {e:#}"),
+            }
+        }
+    }
+
+    let server = match DnsServer::builder()
+        .listeners(config.listen.clone())
+        .workers(config.workers)
+        .queue_size(config.queue_size)
+        .tcp_idle_timeout(config.tcp_idle_timeout)
+        .handler(handler(&config, cache.clone(), &prefetch))
+        .build()
+        .start()
+    {
+        Ok(server) => server,
+        Err(e) => {
+            eprintln!("error: {e:#}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let shutdown_timeout = config.shutdown_timeout;
+    let mut config = config;
+    if let Err(e) = server.wait_for_signals(shutdown_timeout, |server| {
+        config = reload(
+            &args,
+            config.clone(),
+            server,
+            &log,
+            cache.clone(),
+            &prefetch,
+        );
+    }) {
+        eprintln!("error: {e:#}");
+        return ExitCode::FAILURE;
+    }
+    if let (Some(cache), Some(path)) = (&cache, &config.cache_file) {
+        match cache.save(path) {
+            Ok(count) => info!("Saved {count} cached responses to {}", path.display()),
+            Err(e) => error!("Unable to save the cache, {e:#}"),
+        }
+    }
+    ExitCode::SUCCESS
+}
+
+/// Loads the config again, swaps the handler and the log filter, the running config stays when
+/// the new one is invalid. Queries keep being answered the whole time
+fn reload(
+    args: &CliArgs,
+    current: Config,
+    server: &ServerHandle,
+    log: &LogHandle,
+    cache: Option<Arc<DnsCache>>,
+    prefetch: &Arc<WorkerPool>,
+) -> Config {
+    let config = match Config::load(args) {
+        Ok(config) => config,
+        Err(e) => {
+            error!("Keeping the running configuration, {e:#}");
+            return current;
+        }
+    };
+    let restart_needed = current.restart_needed(&config);
+    if !restart_needed.is_empty() {
+        warn!(
+            "Changes to {} only take effect after a restart",
+            restart_needed.join(", ")
+        );
+    }
+    server.set_handler(handler(&config, cache, prefetch));
+    let filter = match log.set_filter(&config.log.filter) {
+        Ok(()) => config.log.filter.clone(),
+        Err(e) => {
+            error!("Keeping the running log filter, {e:#}");
+            current.log.filter.clone()
+        }
+    };
+    info!("Configuration reloaded");
+    // what is actually running, the rest waits for a restart
+    Config {
+        listen: current.listen,
+        workers: current.workers,
+        queue_size: current.queue_size,
+        tcp_idle_timeout: current.tcp_idle_timeout,
+        shutdown_timeout: current.shutdown_timeout,
+        cache_size: current.cache_size,
+        stale_window: current.stale_window,
+        log: LogConfig {
+            filter,
+            ..current.log
+        },
+        ..config
+    }
+}
 
-    DnsServer::start(format!("0.0.0.0:{}", CliArgs::port()).as_str());
+/// Middlewares run in the order they are added: log, policies, local zones, then the handler.
+/// Forwarded queries are answered from `cache` when it has them, popular entries are refreshed
+/// on `prefetch`
+fn handler(
+    config: &Config,
+    cache: Option<Arc<DnsCache>>,
+    prefetch: &Arc<WorkerPool>,
+) -> MiddlewareChain {
+    let mut middlewares: Vec<Arc<dyn Middleware>> = vec![Arc::new(LogMiddleware)];
+    if let Some(allow) = &config.allow {
+        middlewares.push(Arc::new(AclMiddleware::allow(allow.clone())));
+    }
+    if let Some(limit) = config.rate_limit {
+        middlewares.push(Arc::new(RateLimitMiddleware::new(
+            limit.per_second,
+            limit.burst,
+        )));
+    }
+    if !config.block.is_empty() {
+        middlewares.push(Arc::new(BlockMiddleware::new(config.block.clone())));
+    }
+    if !config.zones.is_empty() {
+        middlewares.push(Arc::new(ZoneMiddleware::new(config.zones.clone())));
+    }
+    let handler: Arc<dyn RequestHandler> = match config.mode {
+        Mode::Forward => {
+            let forward = ForwardHandler::new(
+                config
+                    .resolvers
+                    .iter()
+                    .map(|addr| addr.to_string())
+                    .collect(),
+                config.upstream_timeout,
+            );
+            match cache {
+                Some(cache) => Arc::new(forward.cache(cache, prefetch.clone())),
+                None => Arc::new(forward),
+            }
+        }
+        Mode::Mock => Arc::new(MockHandler),
+    };
+    MiddlewareChain::new(middlewares, handler)
 }